@@ -0,0 +1,33 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use bytes::BytesMut;
+use tokio_util::codec::Decoder;
+
+mod error {
+    include!("../../src/error.rs");
+}
+
+mod cmd {
+    include!("../../src/cmd.rs");
+}
+
+mod ftp {
+    include!("../../src/ftp.rs");
+}
+
+mod codec {
+    include!("../../src/codec.rs");
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut codec = codec::FtpCodec;
+    let mut buf = BytesMut::from(data);
+    // Every call either consumes a line or reports that none is complete.
+    loop {
+        match codec.decode(&mut buf) {
+            Ok(None) => break,
+            Ok(Some(_)) | Err(_) => (),
+        }
+    }
+});
@@ -0,0 +1,203 @@
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use async_trait::async_trait;
+
+use crate::cmd::Command;
+use crate::config::{Config, User, ANONYMOUS_USER};
+
+/// What a directory rule of the config can allow.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    List,
+    Delete,
+}
+
+impl Access {
+    fn name(self) -> &'static str {
+        match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::List => "list",
+            Access::Delete => "delete",
+        }
+    }
+
+    /// The access `cmd` needs and the path it needs it on, `None` standing
+    /// for the current directory. Commands not touching files need none.
+    pub fn required(cmd: &Command) -> Option<(Access, Option<&Path>)> {
+        match *cmd {
+            Command::Retr(ref path) | Command::Size(ref path) | Command::Hash(ref path) => {
+                Some((Access::Read, Some(path)))
+            }
+            Command::List(ref path) | Command::Nlst(ref path, _) => Some((Access::List, path.as_deref())),
+            Command::Stor(ref path) | Command::Mkd(ref path) => Some((Access::Write, Some(path))),
+            Command::Dele(ref path) | Command::Rmd(ref path) => Some((Access::Delete, Some(path))),
+            _ => None,
+        }
+    }
+}
+
+/// Whether the directory rules and dropboxes of `config` allow `access` to
+/// `path`, relative to the server root. Paths none of them covers are left to
+/// the user permissions.
+pub fn directory_allows(config: &Config, path: &Path, access: Access) -> bool {
+    let rules = config
+        .directory_rules
+        .iter()
+        .flatten()
+        .map(|rule| (rule.path.as_str(), rule.permissions.iter().any(|name| name == access.name())));
+    let dropboxes = config.dropbox_dirs.iter().flatten().map(|dir| (dir.as_str(), access == Access::Write));
+    let rule = rules
+        .chain(dropboxes)
+        .map(|(dir, allowed)| (Path::new(dir.trim_start_matches('/')), allowed))
+        .filter(|(dir, _)| path.starts_with(dir))
+        .max_by_key(|(dir, _)| dir.components().count());
+    rule.is_none_or(|(_, allowed)| allowed)
+}
+
+/// Checks the credentials sent with USER/PASS.
+///
+/// The server uses the users of its config by default, other backends (a
+/// database, LDAP...) can be plugged in by implementing this trait.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Returns the account details if `pass` is the password of `user`.
+    async fn authenticate(&self, user: &str, pass: &str) -> Option<UserInfo>;
+
+    /// Whether `user` has to send a password, `None` when there's no such
+    /// user. Users logged in without one are authenticated with an empty
+    /// password. Backends that can't tell ask every user for a password.
+    async fn needs_password(&self, _user: &str) -> Option<bool> {
+        Some(true)
+    }
+}
+
+/// What an authenticated user is allowed to do.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserInfo {
+    /// Directory, relative to the server root, the session starts in.
+    pub home: Option<PathBuf>,
+    pub permissions: Permissions,
+    /// Maximum number of bytes the user may store, unlimited when unset.
+    pub quota: Option<u64>,
+    /// Directory, relative to the server root, the user may only upload into:
+    /// nothing can be stored elsewhere nor directories created or removed.
+    pub upload_dir: Option<PathBuf>,
+    /// Transfer rate limit in bytes per second, unlimited when unset.
+    pub max_rate: Option<u64>,
+}
+
+impl UserInfo {
+    pub fn new(permissions: Permissions) -> UserInfo {
+        UserInfo {
+            home: None,
+            permissions,
+            quota: None,
+            upload_dir: None,
+            max_rate: None,
+        }
+    }
+
+    /// Whether the user may run `cmd`.
+    pub fn allows(&self, cmd: &Command) -> bool {
+        match *cmd {
+            Command::Dele(_) | Command::Mkd(_) | Command::Rmd(_) if self.upload_dir.is_some() => false,
+            ref cmd => self.permissions.allows(cmd),
+        }
+    }
+}
+
+/// The account details of `name`, a user of `config`.
+pub fn config_user_info(config: &Config, name: &str) -> UserInfo {
+    let max_rate = match account(config, name).and_then(|account| account.max_rate_bytes_per_sec) {
+        Some(0) => None,
+        Some(rate) => Some(rate),
+        None => config.max_rate_bytes_per_sec,
+    };
+    let info = if config.admin.as_ref().is_some_and(|admin| admin.name == name) {
+        UserInfo::new(Permissions::admin())
+    } else {
+        match config.anonymous_dropbox {
+            // The anonymous user can only drop files, it can't even see them afterwards.
+            Some(ref dropbox) if name == ANONYMOUS_USER => UserInfo {
+                upload_dir: Some(PathBuf::from(dropbox)),
+                ..UserInfo::new(Permissions {
+                    read: false,
+                    ..Permissions::user()
+                })
+            },
+            _ => UserInfo::new(Permissions::user()),
+        }
+    };
+    let quota = account(config, name).and_then(|account| account.quota);
+    let home = account(config, name).and_then(|account| account.home.as_ref()).map(PathBuf::from);
+    UserInfo {
+        home,
+        max_rate,
+        quota,
+        ..info
+    }
+}
+
+/// The user of `config` named `name`, the admin included.
+pub fn account<'a>(config: &'a Config, name: &str) -> Option<&'a User> {
+    match config.admin {
+        Some(ref admin) if admin.name == name => Some(admin),
+        _ => config.users.iter().find(|account| account.name == name),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Permissions {
+    /// Downloading files and listing directories.
+    pub read: bool,
+    /// Uploading files and creating or removing directories.
+    pub write: bool,
+    /// The admin only SITE commands and access to the config file.
+    pub admin: bool,
+}
+
+impl Permissions {
+    pub fn user() -> Permissions {
+        Permissions {
+            read: true,
+            write: true,
+            admin: false,
+        }
+    }
+
+    pub fn admin() -> Permissions {
+        Permissions {
+            admin: true,
+            ..Permissions::user()
+        }
+    }
+
+    /// Whether these permissions allow running `cmd`.
+    pub fn allows(&self, cmd: &Command) -> bool {
+        match *cmd {
+            Command::Retr(_) | Command::List(_) | Command::Nlst(..) | Command::Hash(_) | Command::Size(_) => self.read,
+            Command::Stor(_) | Command::Dele(_) | Command::Mkd(_) | Command::Rmd(_) => self.write,
+            _ => true,
+        }
+    }
+}
+
+/// The users of the server config, as reloaded by SITE RELOAD.
+#[async_trait]
+impl Authenticator for RwLock<Config> {
+    async fn authenticate(&self, user: &str, pass: &str) -> Option<UserInfo> {
+        let config = self.read().unwrap();
+        match account(&config, user) {
+            Some(account) if account.password == pass => Some(config_user_info(&config, user)),
+            _ => None,
+        }
+    }
+
+    async fn needs_password(&self, user: &str) -> Option<bool> {
+        account(&self.read().unwrap(), user).map(|account| !account.password.is_empty())
+    }
+}
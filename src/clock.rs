@@ -0,0 +1,29 @@
+//! Where the current time comes from, so the listings can tell recent files
+//! from old ones.
+
+use time::Timespec;
+
+/// Gives the current time. The server uses the system clock, the tests can
+/// plug in a fixed one to get a predictable output.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Timespec;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timespec {
+        time::get_time()
+    }
+}
+
+/// Always gives the same time.
+#[cfg(test)]
+pub struct FixedClock(pub Timespec);
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> Timespec {
+        self.0
+    }
+}
@@ -1,22 +1,49 @@
 use crate::error::{Error, Result};
-use std::path::{Path, PathBuf};
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::PathBuf;
 use std::str::{self, FromStr};
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, PartialEq)]
 pub enum Command {
-    Auth,
+    Abor,
+    Allo(u64),
+    /// The security mechanism, uppercased.
+    Auth(String),
     Cwd(PathBuf),
+    Dele(PathBuf),
+    Feat,
+    Hash(PathBuf),
+    Help,
     List(Option<PathBuf>),
     Mkd(PathBuf),
+    Mode(TransferMode),
+    /// The path, and whether `-R` asked for a recursive listing.
+    Nlst(Option<PathBuf>, bool),
+    Opts(String),
     Pass(String),
+    /// The protection buffer size, as sent.
+    Pbsz(String),
     NoOp,
-    Port(u16),
+    Port(SocketAddrV4),
     Pasv,
+    /// The data channel protection level, uppercased.
+    Prot(String),
     Pwd,
     Quit,
+    /// "RANG start end", from the FTP range extension draft: the next RETR
+    /// only sends the bytes from `start` up to, but unlike the draft not
+    /// including, `end`. `None` when the range is invalid, which gets a 501
+    /// rather than no reply at all.
+    Rang(Option<(u64, u64)>),
+    Rest(u64),
     Retr(PathBuf),
     Rmd(PathBuf),
+    Site(String),
+    Size(PathBuf),
+    Stat,
     Stor(PathBuf),
+    Stru(FileStructure),
     Syst,
     Type(TransferType),
     CdUp,
@@ -24,23 +51,83 @@ pub enum Command {
     User(String),
 }
 
+// Written by hand so that passwords never end up in the logs.
+impl fmt::Debug for Command {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Command::Abor => formatter.write_str("Abor"),
+            Command::Allo(ref arg) => formatter.debug_tuple("Allo").field(arg).finish(),
+            Command::Auth(ref arg) => formatter.debug_tuple("Auth").field(arg).finish(),
+            Command::Cwd(ref arg) => formatter.debug_tuple("Cwd").field(arg).finish(),
+            Command::Dele(ref arg) => formatter.debug_tuple("Dele").field(arg).finish(),
+            Command::Feat => formatter.write_str("Feat"),
+            Command::Hash(ref arg) => formatter.debug_tuple("Hash").field(arg).finish(),
+            Command::Help => formatter.write_str("Help"),
+            Command::List(ref arg) => formatter.debug_tuple("List").field(arg).finish(),
+            Command::Mkd(ref arg) => formatter.debug_tuple("Mkd").field(arg).finish(),
+            Command::Mode(ref arg) => formatter.debug_tuple("Mode").field(arg).finish(),
+            Command::Nlst(ref arg, recursive) => formatter.debug_tuple("Nlst").field(arg).field(&recursive).finish(),
+            Command::Opts(ref arg) => formatter.debug_tuple("Opts").field(arg).finish(),
+            Command::Pass(_) => formatter.write_str("Pass(<redacted>)"),
+            Command::Pbsz(ref arg) => formatter.debug_tuple("Pbsz").field(arg).finish(),
+            Command::NoOp => formatter.write_str("NoOp"),
+            Command::Port(ref arg) => formatter.debug_tuple("Port").field(arg).finish(),
+            Command::Pasv => formatter.write_str("Pasv"),
+            Command::Prot(ref arg) => formatter.debug_tuple("Prot").field(arg).finish(),
+            Command::Pwd => formatter.write_str("Pwd"),
+            Command::Quit => formatter.write_str("Quit"),
+            Command::Rang(ref arg) => formatter.debug_tuple("Rang").field(arg).finish(),
+            Command::Rest(ref arg) => formatter.debug_tuple("Rest").field(arg).finish(),
+            Command::Retr(ref arg) => formatter.debug_tuple("Retr").field(arg).finish(),
+            Command::Rmd(ref arg) => formatter.debug_tuple("Rmd").field(arg).finish(),
+            Command::Site(ref arg) => formatter.debug_tuple("Site").field(arg).finish(),
+            Command::Size(ref arg) => formatter.debug_tuple("Size").field(arg).finish(),
+            Command::Stat => formatter.write_str("Stat"),
+            Command::Stor(ref arg) => formatter.debug_tuple("Stor").field(arg).finish(),
+            Command::Stru(ref arg) => formatter.debug_tuple("Stru").field(arg).finish(),
+            Command::Syst => formatter.write_str("Syst"),
+            Command::Type(ref arg) => formatter.debug_tuple("Type").field(arg).finish(),
+            Command::CdUp => formatter.write_str("CdUp"),
+            Command::Unknown(ref arg) => formatter.debug_tuple("Unknown").field(arg).finish(),
+            Command::User(ref arg) => formatter.debug_tuple("User").field(arg).finish(),
+        }
+    }
+}
+
 impl AsRef<str> for Command {
     fn as_ref(&self) -> &str {
         match *self {
-            Command::Auth => "AUTH",
+            Command::Abor => "ABOR",
+            Command::Allo(_) => "ALLO",
+            Command::Auth(_) => "AUTH",
             Command::Cwd(_) => "CWD",
+            Command::Dele(_) => "DELE",
+            Command::Feat => "FEAT",
+            Command::Hash(_) => "HASH",
+            Command::Help => "HELP",
             Command::List(_) => "LIST",
+            Command::Nlst(..) => "NLST",
+            Command::Opts(_) => "OPTS",
             Command::Pasv => "PASV",
+            Command::Pbsz(_) => "PBSZ",
             Command::Port(_) => "PORT",
+            Command::Prot(_) => "PROT",
             Command::Pwd => "PWD",
             Command::Quit => "QUIT",
+            Command::Rang(_) => "RANG",
+            Command::Rest(_) => "REST",
             Command::Retr(_) => "RETR",
+            Command::Site(_) => "SITE",
+            Command::Size(_) => "SIZE",
+            Command::Stat => "STAT",
             Command::Stor(_) => "STOR",
+            Command::Stru(_) => "STRU",
             Command::Syst => "SYST",
             Command::Type(_) => "TYPE",
             Command::User(_) => "USER",
             Command::CdUp => "CDUP",
             Command::Mkd(_) => "MKD",
+            Command::Mode(_) => "MODE",
             Command::Rmd(_) => "RMD",
             Command::NoOp => "NOOP",
             Command::Pass(_) => "PASS",
@@ -50,7 +137,23 @@ impl AsRef<str> for Command {
 }
 
 impl Command {
+    /// Whether the command changes the file system.
+    pub fn is_mutating(&self) -> bool {
+        match *self {
+            Command::Stor(_) | Command::Dele(_) | Command::Mkd(_) | Command::Rmd(_) => true,
+            Command::Site(ref args) => {
+                let subcommand = args.split_whitespace().next().unwrap_or_default().to_uppercase();
+                subcommand == "RMDR" || subcommand == "CHMOD"
+            }
+            _ => false,
+        }
+    }
+
     pub fn new(input: Vec<u8>) -> Result<Self> {
+        // Some clients send bare CRLFs as a keepalive.
+        if input.is_empty() {
+            return Ok(Command::NoOp);
+        }
         let mut iter = input.split(|&byte| byte == b' ');
         let mut command = iter
             .next()
@@ -64,44 +167,92 @@ impl Command {
                     .to_string(),
             )
         });
+        // Everything after the command name.
+        let args = input.splitn(2, |&byte| byte == b' ').nth(1);
         let command = match command.as_slice() {
-            b"AUTH" => Command::Auth,
+            b"ABOR" => Command::Abor,
+            b"ALLO" => {
+                let size = data.and_then(|bytes| Ok(str::from_utf8(bytes)?.to_owned()))?;
+                Command::Allo(u64::from_str(&size).map_err(|_| Error::Msg("Invalid size".to_string()))?)
+            }
+            b"AUTH" => Command::Auth(data.and_then(|bytes| Ok(str::from_utf8(bytes)?.to_uppercase()))?),
             b"CWD" => Command::Cwd(
-                data.and_then(|bytes| Ok(Path::new(str::from_utf8(bytes)?).to_path_buf()))?,
+                data.and_then(path_arg)?,
+            ),
+            b"FEAT" => Command::Feat,
+            b"DELE" => Command::Dele(
+                data.and_then(path_arg)?,
             ),
-            b"LIST" => Command::List(
-                data.and_then(|bytes| Ok(Path::new(str::from_utf8(bytes)?).to_path_buf()))
-                    .ok(),
+            b"HELP" => Command::Help,
+            b"HASH" => Command::Hash(
+                data.and_then(path_arg)?,
             ),
+            b"LIST" => Command::List(list_path(args)),
+            b"NLST" => Command::Nlst(list_path(args), recursive_flag(args)),
             b"PASV" => Command::Pasv,
+            b"PBSZ" => Command::Pbsz(data.and_then(|bytes| Ok(str::from_utf8(bytes)?.to_owned()))?),
             b"PORT" => {
-                let addr = data?
+                // h1,h2,h3,h4,p1,p2
+                let fields = data?
                     .split(|&byte| byte == b',')
-                    .filter_map(|bytes| {
+                    .map(|bytes| {
                         str::from_utf8(bytes)
                             .ok()
-                            .and_then(|string| u8::from_str(string).ok())
+                            .and_then(|string| u8::from_str(string.trim()).ok())
                     })
-                    .collect::<Vec<u8>>();
-                if addr.len() != 6 {
-                    return Err("Invalid address/port".into());
-                }
-                let port = (addr[4] as u16) << 8 | (addr[5] as u16);
+                    .collect::<Option<Vec<u8>>>();
+                let fields = match fields {
+                    Some(ref fields) if fields.len() == 6 => fields,
+                    _ => return Err("Invalid address/port".into()),
+                };
+                let ip = Ipv4Addr::new(fields[0], fields[1], fields[2], fields[3]);
+                let port = (fields[4] as u16) << 8 | (fields[5] as u16);
                 if port <= 1024 {
                     return Err("Port can't be less than
     10025"
                         .into());
                 }
-                Command::Port(port)
+                Command::Port(SocketAddrV4::new(ip, port))
             }
+            b"PROT" => Command::Prot(data.and_then(|bytes| Ok(str::from_utf8(bytes)?.to_uppercase()))?),
             b"PWD" => Command::Pwd,
             b"QUIT" => Command::Quit,
+            b"RANG" => {
+                let range = args.and_then(|bytes| str::from_utf8(bytes).ok()).unwrap_or_default();
+                let bounds: Vec<&str> = range.split(' ').collect();
+                Command::Rang(match bounds.as_slice() {
+                    [start, end] => match (u64::from_str(start), u64::from_str(end)) {
+                        (Ok(start), Ok(end)) if start <= end => Some((start, end)),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+            }
+            b"REST" => {
+                let offset = data.and_then(|bytes| Ok(str::from_utf8(bytes)?.to_owned()))?;
+                Command::Rest(u64::from_str(&offset).map_err(|_| Error::Msg("Invalid offset".to_string()))?)
+            }
             b"RETR" => Command::Retr(
-                data.and_then(|bytes| Ok(Path::new(str::from_utf8(bytes)?).to_path_buf()))?,
+                data.and_then(path_arg)?,
+            ),
+            b"SITE" => Command::Site(
+                // SITE arguments are a whole sub-command line, keep everything after "SITE ".
+                args.ok_or_else(|| Error::Msg("no command parameter".to_string()))
+                    .and_then(|bytes| Ok(str::from_utf8(bytes)?.to_owned()))?,
             ),
+            b"SIZE" => Command::Size(
+                data.and_then(path_arg)?,
+            ),
+            b"STAT" => Command::Stat,
             b"STOR" => Command::Stor(
-                data.and_then(|bytes| Ok(Path::new(str::from_utf8(bytes)?).to_path_buf()))?,
+                data.and_then(path_arg)?,
             ),
+            b"STRU" => match data?.first().map(|&byte| FileStructure::from(byte)) {
+                Some(FileStructure::Unknown) | None => {
+                    return Err("command not implemented for that parameter".into())
+                }
+                Some(structure) => Command::Stru(structure),
+            },
             b"SYST" => Command::Syst,
             b"TYPE" =>  {
                 let error = Err("command not implemented for that parameter".into());
@@ -120,16 +271,26 @@ impl Command {
                 }
             },
             b"CDUP" => Command::CdUp,
+            b"MODE" => match data?.first().map(|&byte| TransferMode::from(byte)) {
+                Some(TransferMode::Unknown) | None => {
+                    return Err("command not implemented for that parameter".into())
+                }
+                Some(mode) => Command::Mode(mode),
+            },
             b"MKD" => Command::Mkd(
-                data.and_then(|bytes| Ok(Path::new(str::from_utf8(bytes)?).to_path_buf()))?,
+                data.and_then(path_arg)?,
             ),
             b"RMD" => Command::Rmd(
-                data.and_then(|bytes| Ok(Path::new(str::from_utf8(bytes)?).to_path_buf()))?,
+                data.and_then(path_arg)?,
             ),
             b"USER" => Command::User(
                 data.and_then(|bytes| String::from_utf8(bytes.to_vec()).map_err(Into::into))?,
             ),
             b"NOOP" => Command::NoOp,
+            b"OPTS" => Command::Opts(
+                args.ok_or_else(|| Error::Msg("no command parameter".to_string()))
+                    .and_then(|bytes| Ok(str::from_utf8(bytes)?.to_owned()))?,
+            ),
             b"PASS" => Command::Pass(data.and_then(|bytes| String::from_utf8(bytes.to_vec()).map_err(Into::into))?),
             s => Command::Unknown(str::from_utf8(s).unwrap_or("").to_owned()),
         };
@@ -137,6 +298,34 @@ impl Command {
     }
 }
 
+/// Gets the path of a LIST/NLST command, skipping the `ls`-style options
+/// (`-la`, `-l`...) many clients send before it.
+fn list_path(args: Option<&[u8]>) -> Option<PathBuf> {
+    let args = str::from_utf8(args?).ok()?;
+    let path = args
+        .split(' ')
+        .skip_while(|arg| arg.starts_with('-'))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if path.is_empty() {
+        None
+    } else {
+        Some(PathBuf::from(path))
+    }
+}
+
+/// Whether the `ls`-style options of a listing command include `-R`.
+fn recursive_flag(args: Option<&[u8]>) -> bool {
+    args.and_then(|args| str::from_utf8(args).ok())
+        .is_some_and(|args| args.split(' ').take_while(|arg| arg.starts_with('-')).any(|arg| arg.contains('R')))
+}
+
+/// Decodes a path argument as UTF-8, exactly as typed: names in another
+/// Unicode form than the one on disk are matched later, after OPTS UTF8 ON.
+fn path_arg(bytes: &[u8]) -> Result<PathBuf> {
+    Ok(PathBuf::from(str::from_utf8(bytes)?))
+}
+
 fn to_uppercase(data: &mut [u8]) {
     for byte in data {
         if *byte >= 'a' as u8 && *byte <= 'z' as u8 {
@@ -160,3 +349,156 @@ impl From<u8> for TransferType {
         }
     }
 }
+
+impl TransferType {
+    pub fn name(self) -> &'static str {
+        match self {
+            TransferType::Ascii => "ASCII",
+            TransferType::Image => "Image",
+            TransferType::Unknown => "Unknown",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferMode {
+    Stream,
+    Block,
+    Compressed,
+    Unknown,
+}
+impl From<u8> for TransferMode {
+    fn from(c: u8) -> TransferMode {
+        match c.to_ascii_uppercase() {
+            b'S' => TransferMode::Stream,
+            b'B' => TransferMode::Block,
+            b'C' => TransferMode::Compressed,
+            _ => TransferMode::Unknown,
+        }
+    }
+}
+
+impl TransferMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            TransferMode::Stream => "Stream",
+            TransferMode::Block => "Block",
+            TransferMode::Compressed => "Compressed",
+            TransferMode::Unknown => "Unknown",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileStructure {
+    File,
+    Record,
+    Page,
+    Unknown,
+}
+impl From<u8> for FileStructure {
+    fn from(c: u8) -> FileStructure {
+        match c.to_ascii_uppercase() {
+            b'F' => FileStructure::File,
+            b'R' => FileStructure::Record,
+            b'P' => FileStructure::Page,
+            _ => FileStructure::Unknown,
+        }
+    }
+}
+
+impl FileStructure {
+    pub fn name(self) -> &'static str {
+        match self {
+            FileStructure::File => "File",
+            FileStructure::Record => "Record",
+            FileStructure::Page => "Page",
+            FileStructure::Unknown => "Unknown",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, FileStructure, TransferMode};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_debug_redacts_password() {
+        let cmd = Command::new(b"PASS hunter2".to_vec()).unwrap();
+        let output = format!("{:?}", cmd);
+        assert!(!output.contains("hunter2"));
+        assert_eq!(output, "Pass(<redacted>)");
+        assert_eq!(format!("{:?}", Command::User("ferris".to_owned())), "User(\"ferris\")");
+    }
+
+    #[test]
+    fn test_mode_stru() {
+        assert_eq!(Command::new(b"MODE S".to_vec()).unwrap(), Command::Mode(TransferMode::Stream));
+        assert_eq!(Command::new(b"MODE b".to_vec()).unwrap(), Command::Mode(TransferMode::Block));
+        assert_eq!(Command::new(b"STRU F".to_vec()).unwrap(), Command::Stru(FileStructure::File));
+        assert!(Command::new(b"MODE X".to_vec()).is_err());
+        assert!(Command::new(b"STRU".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_auth() {
+        assert_eq!(Command::new(b"AUTH tls".to_vec()).unwrap(), Command::Auth("TLS".to_owned()));
+        assert_eq!(Command::new(b"AUTH SSL".to_vec()).unwrap(), Command::Auth("SSL".to_owned()));
+        assert!(Command::new(b"AUTH".to_vec()).is_err());
+        assert_eq!(Command::new(b"PBSZ 0".to_vec()).unwrap(), Command::Pbsz("0".to_owned()));
+        assert_eq!(Command::new(b"PROT c".to_vec()).unwrap(), Command::Prot("C".to_owned()));
+        assert!(Command::new(b"PROT".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_rang() {
+        assert_eq!(Command::new(b"RANG 10 20".to_vec()).unwrap(), Command::Rang(Some((10, 20))));
+        assert_eq!(Command::new(b"RANG 0 0".to_vec()).unwrap(), Command::Rang(Some((0, 0))));
+        assert_eq!(Command::new(b"RANG 20 10".to_vec()).unwrap(), Command::Rang(None));
+        assert_eq!(Command::new(b"RANG 10".to_vec()).unwrap(), Command::Rang(None));
+        assert_eq!(Command::new(b"RANG 10 x".to_vec()).unwrap(), Command::Rang(None));
+        assert_eq!(Command::new(b"RANG".to_vec()).unwrap(), Command::Rang(None));
+    }
+
+    #[test]
+    fn test_port() {
+        assert_eq!(
+            Command::new(b"PORT 127,0,0,1,7,138".to_vec()).unwrap(),
+            Command::Port("127.0.0.1:1930".parse().unwrap())
+        );
+        assert_eq!(
+            Command::new(b"PORT 192,168,1,20,255,255".to_vec()).unwrap(),
+            Command::Port("192.168.1.20:65535".parse().unwrap())
+        );
+        assert!(Command::new(b"PORT 127,0,0,1,7".to_vec()).is_err());
+        assert!(Command::new(b"PORT 127,0,0,1,7,138,1".to_vec()).is_err());
+        assert!(Command::new(b"PORT 127,0,0,256,7,138".to_vec()).is_err());
+        assert!(Command::new(b"PORT 127,0,0,1,7,300".to_vec()).is_err());
+        assert!(Command::new(b"PORT 127,0,0,1,x,138".to_vec()).is_err());
+        assert!(Command::new(b"PORT".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_is_mutating() {
+        for line in &["STOR a", "MKD a", "RMD a", "SITE RMDR a", "site rmdr a", "SITE CHMOD 644 a"] {
+            assert!(Command::new(line.as_bytes().to_vec()).unwrap().is_mutating(), "{}", line);
+        }
+        for line in &["RETR a", "LIST", "CWD a", "SITE WHO", "SITE UMASK 022", "SIZE a"] {
+            assert!(!Command::new(line.as_bytes().to_vec()).unwrap().is_mutating(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_path_as_typed() {
+        let composed = Command::new("RETR \u{e9}t\u{e9}.txt".as_bytes().to_vec()).unwrap();
+        let decomposed = Command::new("RETR e\u{301}te\u{301}.txt".as_bytes().to_vec()).unwrap();
+        assert_eq!(composed, Command::Retr(PathBuf::from("\u{e9}t\u{e9}.txt")));
+        assert_eq!(decomposed, Command::Retr(PathBuf::from("e\u{301}te\u{301}.txt")));
+        assert_eq!(
+            Command::new("LIST -la Cafe\u{301}".as_bytes().to_vec()).unwrap(),
+            Command::List(Some(PathBuf::from("Cafe\u{301}")))
+        );
+        assert!(Command::new(b"CWD caf\xe9".to_vec()).is_err());
+    }
+}
@@ -34,10 +34,27 @@ impl Encoder<Answer> for FtpCodec {
     type Error = io::Error;
 
     fn encode(&mut self, answer: Answer, buf: &mut BytesMut) -> io::Result<()> {
-        let answer = if answer.message.is_empty() {
+        // A trailing newline doesn't start another line.
+        let message = answer.message.trim_end_matches('\n');
+        let answer = if message.is_empty() {
             format!("{}\r\n", answer.code as u32)
+        } else if message.contains('\n') {
+            // Multi-line reply as described in RFC 959: "123-First line", then
+            // the text lines, then "123 Last line".
+            let lines: Vec<&str> = message.lines().collect();
+            let mut reply = format!("{}-{}\r\n", answer.code as u32, lines[0]);
+            for line in &lines[1..lines.len() - 1] {
+                // A text line mustn't look like the end of the reply.
+                if line.as_bytes().first().is_some_and(u8::is_ascii_digit) {
+                    reply.push(' ');
+                }
+                reply.push_str(line);
+                reply.push_str("\r\n");
+            }
+            reply.push_str(&format!("{} {}\r\n", answer.code as u32, lines[lines.len() - 1]));
+            reply
         } else {
-            format!("{} {}\r\n", answer.code as u32, answer.message)
+            format!("{} {}\r\n", answer.code as u32, message)
         };
 
         buf.extend(answer.as_bytes());
@@ -94,6 +111,22 @@ mod tests {
         assert_eq!(buf, format!("425\r\n"), "Buffer contains 425");
     }
 
+    #[test]
+    fn test_encoder_multiline() {
+        let mut codec = FtpCodec;
+        let answer = Answer::new(ResultCode::Ok, "first\n second\n200 third\nend");
+        let mut buf = BytesMut::new();
+        let result = codec.encode(answer, &mut buf);
+        assert!(result.is_ok());
+        assert_eq!(buf, "200-first\r\n second\r\n 200 third\r\n200 end\r\n");
+
+        let answer = Answer::new(ResultCode::Ok, "text\n");
+        let mut buf = BytesMut::new();
+        let result = codec.encode(answer, &mut buf);
+        assert!(result.is_ok());
+        assert_eq!(buf, "200 text\r\n");
+    }
+
     #[test]
     fn test_decoder() {
         let mut codec = FtpCodec;
@@ -117,4 +150,48 @@ mod tests {
         let command = result.unwrap();
         assert_eq!(command, Some(Command::List(Some(PathBuf::from("/tmp")))));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_decoder_list_options() {
+        let mut codec = FtpCodec;
+        let mut buf = BytesMut::new();
+        buf.extend(b"LIST -la\r\nLIST -l /pub\r\nLIST\r\nNLST -a dir\r\nNLST -aR\r\n");
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Command::List(None)));
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Command::List(Some(PathBuf::from("/pub"))))
+        );
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Command::List(None)));
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Command::Nlst(Some(PathBuf::from("dir")), false))
+        );
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Command::Nlst(None, true)));
+    }
+
+    #[test]
+    fn test_decoder_pipelined() {
+        let mut codec = FtpCodec;
+        let mut buf = BytesMut::new();
+        buf.extend(b"USER a\r\nPASS b\r\nNO");
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Command::User("a".to_owned())));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Command::Pass("b".to_owned())));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(buf, "NO");
+
+        buf.extend(b"OP\r\n");
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Command::NoOp));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decoder_empty_line() {
+        let mut codec = FtpCodec;
+        let mut buf = BytesMut::new();
+        buf.extend(b"\r\n\r\nPWD\r\n");
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Command::NoOp));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Command::NoOp));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Command::Pwd));
+        assert!(buf.is_empty());
+    }
+}
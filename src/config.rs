@@ -1,24 +1,187 @@
 
-#[derive(Clone, Deserialize, Serialize)]
+#[derive(Clone, Default, Deserialize, PartialEq, Serialize)]
 pub struct Config {
     pub server_port: Option<u16>,
     pub server_addr: Option<String>,
+    /// Addresses ("ip:port") to listen on, replacing `server_addr`/`server_port`.
+    pub listen_addrs: Option<Vec<String>>,
+    /// Addresses ("ip:port", usually on port 990) of implicit FTPS listeners,
+    /// where connections are TLS from the first byte. Needs `tls_cert` and
+    /// `tls_key`.
+    pub implicit_tls_addrs: Option<Vec<String>>,
+    /// PEM file holding the certificate chain used for FTPS.
+    pub tls_cert: Option<String>,
+    /// PEM file holding the private key of `tls_cert`.
+    pub tls_key: Option<String>,
+    /// Oldest TLS version accepted, "1.2" (the default) or "1.3".
+    pub tls_min_version: Option<String>,
+    /// Inclusive range of ports PASV listens on, any free port when unset.
+    pub passive_port_range: Option<(u16, u16)>,
+    /// Seconds a connection has to log in before being closed.
+    pub login_timeout: Option<u64>,
+    /// Milliseconds to wait before greeting a new connection, slowing down
+    /// scanners. No delay when unset.
+    pub greeting_delay_ms: Option<u64>,
+    /// Seconds a logged in connection may stay silent before being closed.
+    pub idle_timeout: Option<u64>,
+    /// Longest idle timeout an admin may set with SITE IDLE, at least
+    /// `idle_timeout`.
+    pub max_idle_timeout: Option<u64>,
+    /// Seconds PORT waits for the connection to the client's address.
+    pub active_connect_timeout: Option<u64>,
+    /// Seconds a control connection stays silent before TCP keepalive probes
+    /// are sent, 0 to disable them.
+    pub tcp_keepalive: Option<u64>,
+    /// TCP_NODELAY on control and data sockets, on by default.
+    pub tcp_nodelay: Option<bool>,
+    /// SO_SNDBUF of control and data sockets, the system default when unset.
+    pub socket_send_buffer: Option<usize>,
+    /// SO_RCVBUF of control and data sockets, the system default when unset.
+    pub socket_recv_buffer: Option<usize>,
+    /// Bytes read from or written to a data connection at once, 64 KiB by
+    /// default. When set, it's also the SO_SNDBUF and SO_RCVBUF of data
+    /// sockets. Larger buffers keep high-latency, high-bandwidth links busy
+    /// at the cost of that much memory per transfer; when unset the kernel
+    /// tunes the socket buffers itself, which is usually best.
+    pub data_buffer_size: Option<usize>,
+    /// Seconds the transfers running when the server is asked to stop get to
+    /// finish before their connections are closed.
+    pub shutdown_grace_period: Option<u64>,
+    /// Default algorithm of the HASH command: "CRC32", "MD5" or "SHA-256".
+    pub hash_algorithm: Option<String>,
+    /// Octal umask (e.g. "022") applied to uploaded files and created
+    /// directories on Unix. Modes changed afterwards by an explicit command
+    /// such as SITE CHMOD aren't masked.
+    pub umask: Option<String>,
+    /// Lets MKD create the missing parents of the directory too, like
+    /// `mkdir -p`. Off by default.
+    pub recursive_mkd: Option<bool>,
+    /// How many levels of subdirectories `NLST -R` descends into.
+    pub max_list_depth: Option<u32>,
+    /// Most entries a single LIST or NLST sends, so a directory of millions
+    /// of files can't keep a client busy forever. The listing is then cut
+    /// short and the final reply says so. No limit when unset.
+    pub max_list_entries: Option<u64>,
+    /// Consecutive failed logins after which an account gets locked.
+    pub max_login_failures: Option<u32>,
+    /// Seconds a locked account stays locked.
+    pub lockout_duration: Option<u64>,
+    /// New connections a single IP address may open per
+    /// `connection_rate_window`, unlimited when unset.
+    pub connection_rate_limit: Option<u32>,
+    /// Length in seconds of the window `connection_rate_limit` applies to.
+    pub connection_rate_window: Option<u64>,
+    /// Transfer rate limit of a data connection in bytes per second,
+    /// unlimited when unset.
+    pub max_rate_bytes_per_sec: Option<u64>,
+    /// Refuses every command changing the file system, for serving a mirror.
+    pub read_only: Option<bool>,
+    /// Confines the process to the server root with chroot(2) once the
+    /// listeners are bound, so the path checks are no longer the only
+    /// barrier. Unix only, and the server has to be started as root: it
+    /// refuses to start otherwise rather than run unconfined. Needs
+    /// `run_as_user`, a process staying root could leave the chroot.
+    pub chroot: Option<bool>,
+    /// User, by name or uid, the server switches to once the listeners are
+    /// bound (and after the chroot), so it can be started as root to listen
+    /// on port 21 without staying root. Unix only; the server doesn't start
+    /// if the switch fails.
+    pub run_as_user: Option<String>,
+    /// Group, by name or gid, to switch to along with `run_as_user`, the
+    /// primary group of that user when unset.
+    pub run_as_group: Option<String>,
+    /// Directory, relative to the server root, the "anonymous" user can upload
+    /// into. When set, that user can't download, list or store anything else.
+    pub anonymous_dropbox: Option<String>,
+    /// Commands (e.g. "DELE", "SITE") refused with a 502 and left out of FEAT
+    /// and HELP.
+    pub disabled_commands: Option<Vec<String>>,
+    /// Expects every connection to start with a PROXY protocol v1 header, as
+    /// sent by HAProxy and other load balancers, and takes the client address
+    /// from it. Connections without a valid header are dropped.
+    pub accept_proxy_protocol: Option<bool>,
+    /// Advertises UTF-8 path support in the greeting and FEAT, which some
+    /// clients (Windows ones notably) look for before using UTF-8 names.
+    pub advertise_utf8: Option<bool>,
+    /// File, relative to the server root, sent along with the reply to a
+    /// successful login, "%u" standing for the user name.
+    pub motd: Option<String>,
+    /// Directory sessions start in after logging in, relative to the home of
+    /// the user or else to the server root. Sessions start in the home, or
+    /// at "/", when unset or when it isn't an existing directory.
+    pub default_dir: Option<String>,
     pub users: Vec<User>,
     pub admin: Option<User>,
+    /// Transfer type new connections start in, either "ascii" or "binary".
+    pub default_transfer_type: Option<String>,
+    /// Refuses RETR in ASCII mode of files that look binary (a NUL byte in
+    /// their first block), which the line ending translation would corrupt.
+    pub strict_ascii: Option<bool>,
+    /// Limits what anybody, whatever their own permissions, may do under
+    /// some directories.
+    pub directory_rules: Option<Vec<DirectoryRule>>,
+    /// Upload-only directories, relative to the server root: files can be
+    /// stored there but not downloaded, listed nor deleted. The same as a
+    /// directory rule only allowing "write".
+    pub dropbox_dirs: Option<Vec<String>>,
 }
 
-#[derive(Clone, Deserialize, Serialize)]
+#[derive(Clone, Default, Deserialize, PartialEq, Serialize)]
 pub struct User {
     pub name: String,
     pub password: String,
+    /// Overrides `max_rate_bytes_per_sec` of the server for this user, 0
+    /// meaning unlimited.
+    pub max_rate_bytes_per_sec: Option<u64>,
+    /// Bytes the user may store under their home directory, unlimited when
+    /// unset. Needs `home`.
+    pub quota: Option<u64>,
+    /// Directory, relative to the server root, the user starts in and their
+    /// quota is counted in.
+    pub home: Option<String>,
+    /// Overrides `default_dir` of the server for this user. Like it, it is
+    /// relative to the home of the user when they have one.
+    pub default_dir: Option<String>,
 }
 
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+pub struct DirectoryRule {
+    /// Directory, relative to the server root, the rule applies to along
+    /// with everything below it. The deepest matching rule wins.
+    pub path: String,
+    /// What is allowed there: any of "read", "write", "list" and "delete".
+    pub permissions: Vec<String>,
+}
+
+/// The names `DirectoryRule::permissions` accepts.
+pub const DIRECTORY_PERMISSIONS: [&str; 4] = ["read", "write", "list", "delete"];
+
+use std::collections::HashSet;
 use std::fs::File;
-use std::path::Path;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Component, Path};
 use std::io::{Read, Write};
 
 use toml;
+
+use crate::cmd::TransferType;
+use crate::hash::HashAlgorithm;
+use crate::tls;
+use crate::error::{Error, Result};
 pub const DEFAULT_PORT: u16 = 1234;
+/// Name of the account `anonymous_dropbox` applies to.
+pub const ANONYMOUS_USER: &str = "anonymous";
+pub const DEFAULT_LOGIN_TIMEOUT: u64 = 30;
+pub const DEFAULT_IDLE_TIMEOUT: u64 = 600;
+pub const DEFAULT_MAX_IDLE_TIMEOUT: u64 = 7200;
+pub const DEFAULT_ACTIVE_CONNECT_TIMEOUT: u64 = 10;
+pub const DEFAULT_TCP_KEEPALIVE: u64 = 60;
+pub const DEFAULT_SHUTDOWN_GRACE_PERIOD: u64 = 30;
+pub const DEFAULT_DATA_BUFFER_SIZE: usize = 64 * 1024;
+pub const DEFAULT_MAX_LIST_DEPTH: u32 = 16;
+pub const DEFAULT_MAX_LOGIN_FAILURES: u32 = 5;
+pub const DEFAULT_LOCKOUT_DURATION: u64 = 300;
+pub const DEFAULT_CONNECTION_RATE_WINDOW: u64 = 60;
 
 fn get_content<P: AsRef<Path>>(file_path: &P) -> Option<String> {
     let mut file = File::open(file_path).ok()?;
@@ -27,27 +190,480 @@ fn get_content<P: AsRef<Path>>(file_path: &P) -> Option<String> {
     Some(content)
 }
 
+// Config files are TOML, unless their name ends in ".json".
+fn is_json<P: AsRef<Path>>(file_path: &P) -> bool {
+    file_path.as_ref().extension().is_some_and(|extension| extension == "json")
+}
+
 impl Config {
-    pub fn new<P: AsRef<Path>>(file_path: P) -> Option<Config> {
+    pub fn new<P: AsRef<Path>>(file_path: P) -> Result<Config> {
         if let Some(content) = get_content(&file_path) {
-            toml::from_str(&content).ok()
+            if is_json(&file_path) {
+                serde_json::from_str(&content).map_err(|error| Error::Msg(error.to_string()))
+            } else {
+                Config::parse(&content)
+            }
         } else {
             println!("No config file found so creating a new one in {}", file_path.as_ref().display());
 
             let config = Config {
                 server_port: Some(DEFAULT_PORT),
                 server_addr: Some("127.0.0.1".to_owned()),
+                listen_addrs: None,
+                implicit_tls_addrs: None,
+                tls_cert: None,
+                tls_key: None,
+                tls_min_version: None,
+                passive_port_range: None,
+                login_timeout: None,
+                greeting_delay_ms: None,
+                idle_timeout: None,
+                max_idle_timeout: None,
+                active_connect_timeout: None,
+                tcp_keepalive: None,
+                tcp_nodelay: None,
+                socket_send_buffer: None,
+                socket_recv_buffer: None,
+                data_buffer_size: None,
+                shutdown_grace_period: None,
+                hash_algorithm: None,
+                umask: None,
+                recursive_mkd: None,
+                max_list_depth: None,
+                max_list_entries: None,
+                max_login_failures: None,
+                lockout_duration: None,
+                connection_rate_limit: None,
+                connection_rate_window: None,
+                max_rate_bytes_per_sec: None,
+                read_only: None,
+                chroot: None,
+                run_as_user: None,
+                run_as_group: None,
+                anonymous_dropbox: None,
+                disabled_commands: None,
+                accept_proxy_protocol: None,
+                advertise_utf8: None,
+                motd: None,
+                default_dir: None,
                 admin: None,
+                default_transfer_type: None,
+                strict_ascii: None,
+                directory_rules: None,
+                dropbox_dirs: None,
                 users: vec![User {
                     name: "annoymous".to_owned(),
                     password: "".to_owned(),
+                    ..Default::default()
                 }],
             };
 
-            let content = toml::to_string(&config).expect("Serialization failed");
+            let content = if is_json(&file_path) {
+                serde_json::to_string_pretty(&config).expect("Serialization failed")
+            } else {
+                toml::to_string(&config).expect("Serialization failed")
+            };
             let mut file = File::create(file_path.as_ref()).expect("could't create file...");
             writeln!(file, "{}", content).expect("could't fulfill config file...");
-            Some(config)
+            Ok(config)
+        }
+    }
+
+    /// Whether `command`, a command name such as "DELE", is disabled.
+    pub fn is_disabled(&self, command: &str) -> bool {
+        self.disabled_commands
+            .as_ref()
+            .is_some_and(|commands| commands.iter().any(|disabled| disabled.eq_ignore_ascii_case(command)))
+    }
+
+    /// Parses the content of a config file.
+    pub fn parse(content: &str) -> Result<Config> {
+        toml::from_str(content).map_err(|error| Error::Msg(error.to_string()))
+    }
+
+    /// Addresses the server listens on, assuming the config was validated.
+    pub fn listen_addrs(&self) -> Vec<SocketAddr> {
+        match self.listen_addrs {
+            Some(ref addrs) => addrs.iter().filter_map(|addr| addr.parse().ok()).collect(),
+            None => {
+                let ip = self.server_addr.as_ref().and_then(|addr| addr.parse().ok());
+                let port = self.server_port.unwrap_or(DEFAULT_PORT);
+                vec![SocketAddr::new(ip.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)), port)]
+            }
+        }
+    }
+
+    pub fn implicit_tls_addrs(&self) -> Vec<SocketAddr> {
+        self.implicit_tls_addrs
+            .iter()
+            .flatten()
+            .filter_map(|addr| addr.parse().ok())
+            .collect()
+    }
+
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+            .as_ref()
+            .and_then(|algorithm| HashAlgorithm::from_name(algorithm))
+            .unwrap_or(HashAlgorithm::Sha256)
+    }
+
+    pub fn umask(&self) -> Option<u32> {
+        self.umask.as_ref().and_then(|umask| u32::from_str_radix(umask, 8).ok())
+    }
+
+    pub fn transfer_type(&self) -> TransferType {
+        match self.default_transfer_type.as_ref().map(|typ| typ.to_lowercase()) {
+            Some(ref typ) if typ == "binary" || typ == "image" => TransferType::Image,
+            _ => TransferType::Ascii,
+        }
+    }
+
+    /// Checks the invariants serde can't express.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        let mut names = HashSet::new();
+        for user in &self.users {
+            if user.name.is_empty() {
+                return Err("a user has an empty name".to_owned());
+            }
+            if !names.insert(&user.name) {
+                return Err(format!("user \"{}\" is defined more than once", user.name));
+            }
+        }
+        for user in self.users.iter().chain(self.admin.iter()) {
+            match user.home {
+                Some(ref home) if Path::new(home).components().any(|component| component == Component::ParentDir) => {
+                    return Err(format!("home \"{}\" of \"{}\" must stay in the server root", home, user.name));
+                }
+                None if user.quota.is_some() => {
+                    return Err(format!("user \"{}\" has a quota but no home", user.name));
+                }
+                _ => {}
+            }
+        }
+        if let Some(ref admin) = self.admin {
+            if admin.name.is_empty() {
+                return Err("the admin has an empty name".to_owned());
+            }
+            if names.contains(&admin.name) {
+                return Err(format!("admin \"{}\" is also defined as a regular user", admin.name));
+            }
+        }
+        if let Some(ref server_addr) = self.server_addr {
+            if server_addr.parse::<IpAddr>().is_err() {
+                return Err(format!("invalid server address \"{}\"", server_addr));
+            }
+        }
+        for addr in self.listen_addrs.iter().flatten() {
+            if addr.parse::<SocketAddr>().is_err() {
+                return Err(format!("invalid listen address \"{}\"", addr));
+            }
+        }
+        for addr in self.implicit_tls_addrs.iter().flatten() {
+            if addr.parse::<SocketAddr>().is_err() {
+                return Err(format!("invalid implicit TLS address \"{}\"", addr));
+            }
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err("tls_cert and tls_key must be set together".to_owned());
+        }
+        if self.implicit_tls_addrs.as_ref().is_some_and(|addrs| !addrs.is_empty()) && self.tls_cert.is_none() {
+            return Err("implicit TLS listeners need tls_cert and tls_key".to_owned());
+        }
+        if self.run_as_group.is_some() && self.run_as_user.is_none() {
+            return Err("run_as_group needs run_as_user".to_owned());
+        }
+        if self.chroot == Some(true) && self.run_as_user.is_none() {
+            return Err("chroot needs run_as_user".to_owned());
+        }
+        if let Some(ref version) = self.tls_min_version {
+            if tls::versions(version).is_none() {
+                return Err(format!("unsupported TLS version \"{}\", use \"1.2\" or \"1.3\"", version));
+            }
+        }
+        if let Some((low, high)) = self.passive_port_range {
+            if low > high {
+                return Err(format!("invalid passive port range {}-{}", low, high));
+            }
+        }
+        match (self.idle_timeout, self.max_idle_timeout) {
+            (_, Some(0)) => return Err("max_idle_timeout must be positive".to_owned()),
+            (Some(idle), Some(max)) if idle > max => {
+                return Err(format!("idle_timeout {} is above max_idle_timeout {}", idle, max))
+            }
+            _ => {}
+        }
+        if self.max_list_entries == Some(0) {
+            return Err("max_list_entries must be positive".to_owned());
+        }
+        if self.data_buffer_size == Some(0) {
+            return Err("data_buffer_size must be positive".to_owned());
+        }
+        if let Some(ref algorithm) = self.hash_algorithm {
+            if HashAlgorithm::from_name(algorithm).is_none() {
+                return Err(format!("unknown hash algorithm \"{}\"", algorithm));
+            }
+        }
+        if let Some(ref umask) = self.umask {
+            match u32::from_str_radix(umask, 8) {
+                Ok(umask) if umask <= 0o777 => {}
+                _ => return Err(format!("invalid umask \"{}\"", umask)),
+            }
+        }
+        if let Some(ref dropbox) = self.anonymous_dropbox {
+            let path = Path::new(dropbox);
+            if path.has_root() || path.components().any(|component| component == Component::ParentDir) {
+                return Err(format!("anonymous dropbox \"{}\" must be relative to the server root", dropbox));
+            }
+        }
+        for rule in self.directory_rules.iter().flatten() {
+            if Path::new(&rule.path).components().any(|component| component == Component::ParentDir) {
+                return Err(format!("directory rule path \"{}\" must stay in the server root", rule.path));
+            }
+            if let Some(name) = rule.permissions.iter().find(|name| !DIRECTORY_PERMISSIONS.contains(&name.as_str())) {
+                return Err(format!("unknown permission \"{}\" in the rule of \"{}\"", name, rule.path));
+            }
+        }
+        for dir in self.dropbox_dirs.iter().flatten() {
+            if Path::new(dir).components().any(|component| component == Component::ParentDir) {
+                return Err(format!("dropbox directory \"{}\" must stay in the server root", dir));
+            }
+        }
+        if let Some(ref typ) = self.default_transfer_type {
+            match typ.to_lowercase().as_str() {
+                "ascii" | "binary" | "image" => {}
+                _ => return Err(format!("unknown default transfer type \"{}\"", typ)),
+            }
         }
+        Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, DirectoryRule, User};
+
+    fn user(name: &str) -> User {
+        User {
+            name: name.to_owned(),
+            password: "".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn config() -> Config {
+        Config {
+            users: vec![user("ferris"), user("crab")],
+            admin: Some(user("admin")),
+            passive_port_range: Some((50000, 50100)),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_validate_ok() {
+        assert!(config().validate().is_ok());
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_empty_name() {
+        let mut config = config();
+        config.users.push(user(""));
+        assert!(config.validate().is_err());
+
+        let mut config = self::config();
+        config.admin = Some(user(""));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_duplicate_user() {
+        let mut config = config();
+        config.users.push(user("ferris"));
+        assert!(config.validate().unwrap_err().contains("ferris"));
+    }
+
+    #[test]
+    fn test_validate_admin_collision() {
+        let mut config = config();
+        config.admin = Some(user("crab"));
+        assert!(config.validate().unwrap_err().contains("crab"));
+    }
+
+    #[test]
+    fn test_listen_addrs() {
+        let mut config = config();
+        assert_eq!(config.listen_addrs(), vec!["127.0.0.1:1234".parse().unwrap()]);
+        config.server_addr = Some("0.0.0.0".to_owned());
+        config.server_port = Some(21);
+        assert_eq!(config.listen_addrs(), vec!["0.0.0.0:21".parse().unwrap()]);
+
+        config.listen_addrs = Some(vec!["127.0.0.1:2121".to_owned(), "[::1]:2121".to_owned()]);
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.listen_addrs(),
+            vec!["127.0.0.1:2121".parse().unwrap(), "[::1]:2121".parse().unwrap()]
+        );
+
+        config.listen_addrs = Some(vec!["localhost".to_owned()]);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_passive_port_range() {
+        let mut config = config();
+        config.passive_port_range = Some((50100, 50000));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_home() {
+        let mut config = config();
+        config.users[0].quota = Some(1000);
+        assert!(config.validate().is_err());
+        config.users[0].home = Some("home/ferris".to_owned());
+        assert!(config.validate().is_ok());
+        config.users[0].home = Some("../elsewhere".to_owned());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_idle_timeout() {
+        let mut config = config();
+        config.idle_timeout = Some(600);
+        config.max_idle_timeout = Some(600);
+        assert!(config.validate().is_ok());
+        config.max_idle_timeout = Some(300);
+        assert!(config.validate().is_err());
+        config.idle_timeout = None;
+        config.max_idle_timeout = Some(0);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_hash_algorithm() {
+        let mut config = config();
+        config.hash_algorithm = Some("md5".to_owned());
+        assert!(config.validate().is_ok());
+        config.hash_algorithm = Some("sha-1".to_owned());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_umask() {
+        let mut config = config();
+        config.umask = Some("027".to_owned());
+        assert!(config.validate().is_ok());
+        assert_eq!(config.umask(), Some(0o027));
+        config.umask = Some("1777".to_owned());
+        assert!(config.validate().is_err());
+        config.umask = Some("rwx".to_owned());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_transfer_type() {
+        let mut config = config();
+        config.default_transfer_type = Some("ebcdic".to_owned());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_anonymous_dropbox() {
+        let mut config = config();
+        config.anonymous_dropbox = Some("incoming/uploads".to_owned());
+        assert!(config.validate().is_ok());
+        config.anonymous_dropbox = Some("/incoming".to_owned());
+        assert!(config.validate().is_err());
+        config.anonymous_dropbox = Some("incoming/../..".to_owned());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_tls() {
+        let mut config = config();
+        config.implicit_tls_addrs = Some(vec!["127.0.0.1:990".to_owned()]);
+        assert!(config.validate().is_err());
+        config.tls_cert = Some("cert.pem".to_owned());
+        assert!(config.validate().is_err());
+        config.tls_key = Some("key.pem".to_owned());
+        assert!(config.validate().is_ok());
+        assert_eq!(config.implicit_tls_addrs(), vec!["127.0.0.1:990".parse().unwrap()]);
+        config.tls_min_version = Some("1.3".to_owned());
+        assert!(config.validate().is_ok());
+        config.tls_min_version = Some("1.0".to_owned());
+        assert!(config.validate().is_err());
+        config.tls_min_version = None;
+        config.implicit_tls_addrs = Some(vec!["990".to_owned()]);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_run_as() {
+        let mut config = config();
+        config.chroot = Some(true);
+        assert!(config.validate().is_err());
+        config.run_as_group = Some("nogroup".to_owned());
+        assert!(config.validate().is_err());
+        config.run_as_user = Some("nobody".to_owned());
+        assert!(config.validate().is_ok());
+        config.chroot = Some(false);
+        config.run_as_user = None;
+        assert!(config.validate().is_err());
+        config.run_as_group = None;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_json_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_path = dir.path().join("config.toml");
+        let json_path = dir.path().join("config.json");
+        std::fs::write(
+            &toml_path,
+            "server_port = 2121\npassive_port_range = [50000, 50100]\ndisabled_commands = [\"DELE\"]\n\n\
+             [[users]]\nname = \"ferris\"\npassword = \"crab\"\nquota = 1024\n\n\
+             [admin]\nname = \"admin\"\npassword = \"secret\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &json_path,
+            r#"{
+                "server_port": 2121,
+                "passive_port_range": [50000, 50100],
+                "disabled_commands": ["DELE"],
+                "users": [{"name": "ferris", "password": "crab", "quota": 1024}],
+                "admin": {"name": "admin", "password": "secret"}
+            }"#,
+        )
+        .unwrap();
+
+        let from_toml = Config::new(&toml_path).unwrap();
+        assert!(from_toml.users[0].quota == Some(1024));
+        assert!(Config::new(&json_path).unwrap() == from_toml);
+        std::fs::write(&json_path, "{").unwrap();
+        assert!(Config::new(&json_path).is_err());
+
+        // A missing config is created in the format its name asks for.
+        let created = dir.path().join("new.json");
+        let config = Config::new(&created).unwrap();
+        assert!(std::fs::read_to_string(&created).unwrap().trim_start().starts_with('{'));
+        assert!(Config::new(&created).unwrap() == config);
+    }
+
+    #[test]
+    fn test_validate_directory_rules() {
+        let rule = |path: &str, permission: &str| DirectoryRule {
+            path: path.to_owned(),
+            permissions: vec![permission.to_owned()],
+        };
+        let mut config = config();
+        config.directory_rules = Some(vec![rule("/pub", "read"), rule("incoming", "write")]);
+        assert!(config.validate().is_ok());
+        config.directory_rules = Some(vec![rule("/pub/../etc", "read")]);
+        assert!(config.validate().is_err());
+        config.directory_rules = Some(vec![rule("/pub", "execute")]);
+        assert!(config.validate().is_err());
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt::{self, Display, Formatter};
+
 pub struct Answer {
     pub code: ResultCode,
     pub message: String,
@@ -11,7 +13,7 @@ impl Answer {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u32)]
 #[allow(dead_code)]
 pub enum ResultCode {
@@ -32,6 +34,7 @@ pub enum ResultCode {
     ClosingDataConnection = 226,
     EnteringPassiveMode = 227,
     UserLoggedIn = 230,
+    SecurityDataExchangeComplete = 234,
     RequestedFileActionOkay = 250,
     PATHNAMECreated = 257,
     UserNameOkayNeedPassword = 331,
@@ -50,8 +53,132 @@ pub enum ResultCode {
     CommandNotImplementedForThatParameter = 504,
     NotLoggedIn = 530,
     NeedAccountForStoringFiles = 532,
+    ProtectionLevelNotSupported = 536,
     FileNotFound = 550,
     PageTypeUnknown = 551,
     ExceededStorageAllocation = 552,
     FileNameNotAllowed = 553,
 }
+
+#[allow(dead_code)]
+const RESULT_CODES: [ResultCode; 41] = [
+    ResultCode::RestartMarkerReply,
+    ResultCode::ServiceReadInXXXMinutes,
+    ResultCode::DataConnectionAlreadyOpen,
+    ResultCode::FileStatusOk,
+    ResultCode::Ok,
+    ResultCode::CommandNotImplementedSuperfluousAtThisSite,
+    ResultCode::SystemStatus,
+    ResultCode::DirectoryStatus,
+    ResultCode::FileStatus,
+    ResultCode::HelpMessage,
+    ResultCode::SystemType,
+    ResultCode::ServiceReadyForNewUser,
+    ResultCode::ServiceClosingControlConnection,
+    ResultCode::DataConnectionOpen,
+    ResultCode::ClosingDataConnection,
+    ResultCode::EnteringPassiveMode,
+    ResultCode::UserLoggedIn,
+    ResultCode::SecurityDataExchangeComplete,
+    ResultCode::RequestedFileActionOkay,
+    ResultCode::PATHNAMECreated,
+    ResultCode::UserNameOkayNeedPassword,
+    ResultCode::NeedAccountForLogin,
+    ResultCode::RequestedFileActionPendingFurtherInformation,
+    ResultCode::ServiceNotAvailable,
+    ResultCode::CantOpenDataConnection,
+    ResultCode::ConnectionClosed,
+    ResultCode::FileBusy,
+    ResultCode::LocalErrorInProcessing,
+    ResultCode::InsufficientStorageSpace,
+    ResultCode::UnknownCommand,
+    ResultCode::InvalidParameterOrArgument,
+    ResultCode::CommandNotImplemented,
+    ResultCode::BadSequenceOfCommands,
+    ResultCode::CommandNotImplementedForThatParameter,
+    ResultCode::NotLoggedIn,
+    ResultCode::NeedAccountForStoringFiles,
+    ResultCode::ProtectionLevelNotSupported,
+    ResultCode::FileNotFound,
+    ResultCode::PageTypeUnknown,
+    ResultCode::ExceededStorageAllocation,
+    ResultCode::FileNameNotAllowed,
+];
+
+#[allow(dead_code)]
+impl ResultCode {
+    /// Parses a numeric reply code back into a `ResultCode`.
+    pub fn from_code(code: u32) -> Option<ResultCode> {
+        RESULT_CODES.iter().find(|result_code| **result_code as u32 == code).copied()
+    }
+}
+
+impl Display for ResultCode {
+    /// Writes the standard reason phrase of the code.
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        let phrase = match *self {
+            ResultCode::RestartMarkerReply => "Restart marker reply",
+            ResultCode::ServiceReadInXXXMinutes => "Service ready in a few minutes",
+            ResultCode::DataConnectionAlreadyOpen => "Data connection already open; transfer starting",
+            ResultCode::FileStatusOk => "File status okay; about to open data connection",
+            ResultCode::Ok => "Command okay",
+            ResultCode::CommandNotImplementedSuperfluousAtThisSite => "Command not implemented, superfluous at this site",
+            ResultCode::SystemStatus => "System status",
+            ResultCode::DirectoryStatus => "Directory status",
+            ResultCode::FileStatus => "File status",
+            ResultCode::HelpMessage => "Help message",
+            ResultCode::SystemType => "System type",
+            ResultCode::ServiceReadyForNewUser => "Service ready for new user",
+            ResultCode::ServiceClosingControlConnection => "Service closing control connection",
+            ResultCode::DataConnectionOpen => "Data connection open; no transfer in progress",
+            ResultCode::ClosingDataConnection => "Closing data connection",
+            ResultCode::EnteringPassiveMode => "Entering passive mode",
+            ResultCode::UserLoggedIn => "User logged in, proceed",
+            ResultCode::SecurityDataExchangeComplete => "Security data exchange complete",
+            ResultCode::RequestedFileActionOkay => "Requested file action okay, completed",
+            ResultCode::PATHNAMECreated => "Pathname created",
+            ResultCode::UserNameOkayNeedPassword => "User name okay, need password",
+            ResultCode::NeedAccountForLogin => "Need account for login",
+            ResultCode::RequestedFileActionPendingFurtherInformation => "Requested file action pending further information",
+            ResultCode::ServiceNotAvailable => "Service not available, closing control connection",
+            ResultCode::CantOpenDataConnection => "Can't open data connection",
+            ResultCode::ConnectionClosed => "Connection closed; transfer aborted",
+            ResultCode::FileBusy => "Requested file action not taken; file unavailable",
+            ResultCode::LocalErrorInProcessing => "Requested action aborted; local error in processing",
+            ResultCode::InsufficientStorageSpace => "Requested action not taken; insufficient storage space",
+            ResultCode::UnknownCommand => "Syntax error, command unrecognized",
+            ResultCode::InvalidParameterOrArgument => "Syntax error in parameters or arguments",
+            ResultCode::CommandNotImplemented => "Command not implemented",
+            ResultCode::BadSequenceOfCommands => "Bad sequence of commands",
+            ResultCode::CommandNotImplementedForThatParameter => "Command not implemented for that parameter",
+            ResultCode::NotLoggedIn => "Not logged in",
+            ResultCode::NeedAccountForStoringFiles => "Need account for storing files",
+            ResultCode::ProtectionLevelNotSupported => "Requested PROT level not supported by mechanism",
+            ResultCode::FileNotFound => "Requested action not taken; file unavailable",
+            ResultCode::PageTypeUnknown => "Requested action aborted; page type unknown",
+            ResultCode::ExceededStorageAllocation => "Requested file action aborted; exceeded storage allocation",
+            ResultCode::FileNameNotAllowed => "Requested action not taken; file name not allowed",
+        };
+        write!(formatter, "{}", phrase)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ResultCode, RESULT_CODES};
+
+    #[test]
+    fn test_from_code() {
+        for code in RESULT_CODES.iter() {
+            assert_eq!(ResultCode::from_code(*code as u32), Some(*code));
+        }
+        assert_eq!(ResultCode::from_code(226), Some(ResultCode::ClosingDataConnection));
+        assert_eq!(ResultCode::from_code(999), None);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(ResultCode::NotLoggedIn.to_string(), "Not logged in");
+        assert_eq!(ResultCode::Ok as u32, 200);
+    }
+}
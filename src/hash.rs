@@ -0,0 +1,88 @@
+use std::io;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HashAlgorithm {
+    Crc32,
+    Md5,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// Every supported algorithm, in the order FEAT advertises them.
+    pub const ALL: [HashAlgorithm; 3] = [HashAlgorithm::Sha256, HashAlgorithm::Md5, HashAlgorithm::Crc32];
+
+    pub fn from_name(name: &str) -> Option<HashAlgorithm> {
+        match name.to_uppercase().as_str() {
+            "CRC32" => Some(HashAlgorithm::Crc32),
+            "MD5" => Some(HashAlgorithm::Md5),
+            "SHA-256" => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Crc32 => "CRC32",
+            HashAlgorithm::Md5 => "MD5",
+            HashAlgorithm::Sha256 => "SHA-256",
+        }
+    }
+}
+
+enum Hasher {
+    Crc32(crc32fast::Hasher),
+    Md5(md5::Context),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Hasher {
+        match algorithm {
+            HashAlgorithm::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+            HashAlgorithm::Md5 => Hasher::Md5(md5::Context::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match *self {
+            Hasher::Crc32(ref mut hasher) => hasher.update(data),
+            Hasher::Md5(ref mut context) => context.consume(data),
+            Hasher::Sha256(ref mut hasher) => hasher.update(data),
+        }
+    }
+
+    fn finish(self) -> String {
+        match self {
+            Hasher::Crc32(hasher) => format!("{:08x}", hasher.finalize()),
+            Hasher::Md5(context) => format!("{:x}", context.compute()),
+            Hasher::Sha256(hasher) => hasher
+                .finalize()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        }
+    }
+}
+
+/// Hashes the whole file, returning its size along with the hex digest.
+pub async fn hash_file(path: &Path, algorithm: HashAlgorithm) -> io::Result<(u64, String)> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Hasher::new(algorithm);
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let len = file.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+        size += len as u64;
+    }
+    Ok((size, hasher.finish()))
+}
@@ -1,8 +1,16 @@
+mod auth;
+mod clock;
 mod cmd;
 mod codec;
 mod error;
 mod ftp;
 mod config;
+mod hash;
+mod opts;
+mod proxy;
+#[cfg(test)]
+mod testing;
+mod tls;
 
 #[macro_use]
 extern crate serde_derive;
@@ -10,35 +18,162 @@ extern crate serde_derive;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
 use tokio::prelude::*;
-use tokio::fs::File;
+use tokio::fs::{File, OpenOptions};
 use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{delay_for, timeout, timeout_at, Duration, Instant};
 
-use crate::cmd::{Command, TransferType};
+use crate::auth::{account, config_user_info, directory_allows, Access, Authenticator, UserInfo};
+use crate::clock::{Clock, SystemClock};
+use crate::cmd::{Command, FileStructure, TransferMode, TransferType};
 use crate::codec::FtpCodec;
 use crate::error::{Error, Result};
 use crate::ftp::{Answer, ResultCode};
+use crate::hash::{hash_file, HashAlgorithm};
+use crate::opts::Options;
 use futures::prelude::*;
 use futures::stream::SplitSink;
 use futures::stream::SplitStream;
-use futures::{StreamExt};
+use futures::{future, StreamExt};
 use tokio_util::codec::Framed;
+use tokio_rustls::TlsAcceptor;
+use glob::Pattern;
+use unicode_normalization::UnicodeNormalization;
 
 use std::env;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ffi::OsString;
+use std::mem;
+use std::str;
+use std::fmt::{self, Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::path::Path;
 use std::path::PathBuf;
 use std::path::StripPrefixError;
+use std::io::SeekFrom;
 use std::result;
+use std::collections::VecDeque;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
 use std::fs::create_dir;
 use std::fs::read_dir;
+use std::fs::remove_dir;
 use std::fs::remove_dir_all;
+use std::fs::remove_file;
+#[cfg(unix)]
+use std::fs::{set_permissions, Permissions};
 use std::path::Component;
 
 use crate::config::Config;
-use crate::config::DEFAULT_PORT;
+use crate::config::{
+    DEFAULT_ACTIVE_CONNECT_TIMEOUT, DEFAULT_CONNECTION_RATE_WINDOW, DEFAULT_DATA_BUFFER_SIZE, DEFAULT_IDLE_TIMEOUT, DEFAULT_LOCKOUT_DURATION,
+    DEFAULT_LOGIN_TIMEOUT, DEFAULT_MAX_IDLE_TIMEOUT, DEFAULT_MAX_LIST_DEPTH, DEFAULT_MAX_LOGIN_FAILURES, DEFAULT_SHUTDOWN_GRACE_PERIOD,
+    DEFAULT_TCP_KEEPALIVE,
+};
 
 const CONFIG_FILE: &'static str = "config.toml";
+const TRANSFER_CHUNK_SIZE: usize = 64 * 1024;
+// Commands listed by HELP.
+const COMMANDS: &[&str] = &[
+    "ABOR", "ALLO", "AUTH", "CDUP", "CWD", "DELE", "FEAT", "HASH", "HELP", "LIST", "MKD", "MODE", "NLST", "NOOP",
+    "OPTS", "PASS", "PASV", "PBSZ", "PORT", "PROT", "PWD", "QUIT", "RANG", "REST", "RETR", "RMD", "SITE", "SIZE", "STAT", "STOR", "STRU",
+    "SYST", "TYPE", "USER",
+];
+/// Runs a SITE subcommand, given what follows its name.
+type SiteHandler = fn(Client, Option<String>) -> future::BoxFuture<'static, Result<Client>>;
+
+/// A SITE subcommand, as SITE dispatches it and SITE HELP lists it.
+struct SiteCommand {
+    name: &'static str,
+    // Anyone else gets "Permission denied" without the handler running.
+    admin_only: bool,
+    description: &'static str,
+    handler: SiteHandler,
+}
+
+// Sorted by name, the order SITE HELP lists them in.
+const SITE_COMMANDS: &[SiteCommand] = &[
+    SiteCommand {
+        name: "HASH",
+        admin_only: false,
+        description: "Select the algorithm of HASH",
+        handler: |client, args| client.site_hash(args).boxed(),
+    },
+    SiteCommand {
+        name: "HELP",
+        admin_only: false,
+        description: "List the SITE commands",
+        handler: |client, _| client.site_help().boxed(),
+    },
+    SiteCommand {
+        name: "HISTORY",
+        admin_only: true,
+        description: "Show the last commands of the session",
+        handler: |client, _| client.site_history().boxed(),
+    },
+    SiteCommand {
+        name: "IDLE",
+        admin_only: false,
+        description: "Show the idle timeout, or set it (admin only)",
+        handler: |client, args| client.site_idle(args).boxed(),
+    },
+    SiteCommand {
+        name: "KICK",
+        admin_only: true,
+        description: "Disconnect a session",
+        handler: |client, args| client.site_kick(args).boxed(),
+    },
+    SiteCommand {
+        name: "MSG",
+        admin_only: true,
+        description: "Send a message to every other session",
+        handler: |client, args| client.site_msg(args).boxed(),
+    },
+    SiteCommand {
+        name: "QUOTA",
+        admin_only: false,
+        description: "Show the storage used and allowed",
+        handler: |client, args| client.site_quota(args).boxed(),
+    },
+    SiteCommand {
+        name: "RELOAD",
+        admin_only: true,
+        description: "Reload the config file",
+        handler: |client, _| client.site_reload().boxed(),
+    },
+    SiteCommand {
+        name: "RMDR",
+        admin_only: true,
+        description: "Remove a directory and its content",
+        handler: |client, args| client.site_rmdr(args).boxed(),
+    },
+    SiteCommand {
+        name: "UMASK",
+        admin_only: true,
+        description: "Show or set the umask",
+        handler: |client, args| client.site_umask(args).boxed(),
+    },
+    SiteCommand {
+        name: "WHO",
+        admin_only: true,
+        description: "List the connected sessions",
+        handler: |client, _| client.site_who().boxed(),
+    },
+];
+// Extensions advertised by FEAT. Not RANG: its end is excluded, unlike in the
+// draft "RANG STREAM" would promise.
+const FEATURES: &[&str] = &["REST STREAM", "TYPE A;I", "MODE S", "STRU F"];
+// How long PASV waits for the client to open the data connection.
+const DATA_CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+// Number of commands kept for SITE HISTORY.
+const HISTORY_SIZE: usize = 20;
+// Commands kept during a transfer before the client gets disconnected.
+const MAX_PENDING_COMMANDS: usize = 64;
+// Directory entries read at once on the blocking pool while listing.
+const LISTING_BATCH_SIZE: usize = 256;
+// SITE MSG notices waiting for a busy session, newer ones are dropped.
+const MESSAGE_QUEUE_SIZE: usize = 16;
 
 fn invalid_path(path: &Path) -> bool {
     for component in path.components() {
@@ -49,6 +184,180 @@ fn invalid_path(path: &Path) -> bool {
     false
 }
 
+/// The canonical path of `home` and the total size of the files under it,
+/// `None` when it doesn't exist.
+fn home_usage(server_root: &Path, home: PathBuf) -> io::Result<Option<(PathBuf, u64)>> {
+    let home = match resolve_path(server_root, home) {
+        Ok(home) => home,
+        Err(_) => return Ok(None),
+    };
+    let usage = dir_usage(&home)?;
+    Ok(Some((home, usage)))
+}
+
+/// Total size of the files under `dir`, symlinks not followed.
+fn dir_usage(dir: &Path) -> io::Result<u64> {
+    let mut usage = 0;
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            usage += dir_usage(&entry.path())?;
+        } else if metadata.is_file() {
+            usage += metadata.len();
+        }
+    }
+    Ok(usage)
+}
+
+/// Whether `path` exists but isn't a regular file: a directory, or on Unix a
+/// FIFO, socket or device that opening could block on.
+async fn is_special_file(path: &Path) -> bool {
+    metadata(path).await.is_ok_and(|metadata| !metadata.is_file())
+}
+
+async fn is_file(path: &Path) -> bool {
+    metadata(path).await.is_ok_and(|metadata| metadata.is_file())
+}
+
+async fn is_dir(path: &Path) -> bool {
+    metadata(path).await.is_ok_and(|metadata| metadata.is_dir())
+}
+
+/// The metadata of `path`, following symlinks, fetched with `blocking`.
+async fn metadata(path: &Path) -> io::Result<Metadata> {
+    let path = path.to_path_buf();
+    blocking(move || path.metadata()).await
+}
+
+/// Whether the first block of the file holds a NUL byte, which text files
+/// never do.
+async fn looks_binary(path: &Path) -> bool {
+    let path = path.to_path_buf();
+    let first_block = blocking(move || {
+        let mut buf = vec![0; TRANSFER_CHUNK_SIZE];
+        let len = std::fs::File::open(path).and_then(|mut file| std::io::Read::read(&mut file, &mut buf))?;
+        buf.truncate(len);
+        Ok(buf)
+    });
+    first_block.await.is_ok_and(|buf| buf.contains(&0))
+}
+
+fn aborted_by_client() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "aborted by the client")
+}
+
+/// Resolves a client supplied path against the (canonical) server root, failing
+/// with `PermissionDenied` if it ends up outside of it.
+fn resolve_path(server_root: &Path, path: PathBuf) -> io::Result<PathBuf> {
+    let dir = join_root(server_root, path).canonicalize()?;
+    if !dir.starts_with(server_root) {
+        return Err(io::ErrorKind::PermissionDenied.into());
+    }
+    Ok(dir)
+}
+
+/// Like `resolve_path`, for a path that may not exist yet (what STOR or MKD
+/// create): its longest existing ancestor is canonicalized and the missing
+/// components appended back.
+fn resolve_path_allow_missing(server_root: &Path, path: PathBuf) -> io::Result<PathBuf> {
+    let path = join_root(server_root, path);
+    let mut existing = path.as_path();
+    let mut missing = vec![];
+    let mut resolved = loop {
+        match existing.canonicalize() {
+            Ok(resolved) => break resolved,
+            // A dangling symlink would be followed when creating the file.
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound && existing.symlink_metadata().is_err() => {
+                match existing.components().next_back() {
+                    Some(Component::Normal(name)) => missing.push(name),
+                    _ => return Err(io::ErrorKind::PermissionDenied.into()),
+                }
+                existing = existing.parent().ok_or(io::ErrorKind::PermissionDenied)?;
+            }
+            Err(error) => return Err(error),
+        }
+    };
+    for name in missing.into_iter().rev() {
+        resolved.push(name);
+    }
+    if !resolved.starts_with(server_root) {
+        return Err(io::ErrorKind::PermissionDenied.into());
+    }
+    Ok(resolved)
+}
+
+// Client paths are relative to the server root, even the absolute ones.
+fn join_root(server_root: &Path, path: PathBuf) -> PathBuf {
+    server_root.join(if path.has_root() {
+        path.iter().skip(1).collect()
+    } else {
+        path
+    })
+}
+
+/// Turns a client path, which always uses `/`, into a native one.
+fn native_path(path: &Path) -> PathBuf {
+    if cfg!(windows) {
+        PathBuf::from(path.to_string_lossy().replace('/', "\\"))
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// The native form of a path typed by the client, its names matched across
+/// Unicode normalization forms when `utf8` is set (OPTS UTF8 ON).
+fn client_path(server_root: &Path, path: &Path, utf8: bool) -> PathBuf {
+    let path = native_path(path);
+    if utf8 {
+        match_normalized(server_root, &path)
+    } else {
+        path
+    }
+}
+
+/// Replaces each component of `path` missing as typed by the entry of its
+/// directory with the same NFC form, so a name typed composed still finds
+/// the file stored decomposed (as macOS clients upload them) and the other
+/// way around.
+fn match_normalized(server_root: &Path, path: &Path) -> PathBuf {
+    let mut matched = PathBuf::new();
+    for component in path.components() {
+        let mut next = matched.join(component);
+        // Past a "..", the directory read could be outside of the root.
+        let outside = matched.components().any(|component| component == Component::ParentDir);
+        if let (Component::Normal(name), false) = (component, outside) {
+            let wanted = name.to_str().map(|name| name.nfc().collect::<String>());
+            if let (Some(wanted), Err(_)) = (wanted, join_root(server_root, next.clone()).symlink_metadata()) {
+                let entries = read_dir(join_root(server_root, matched.clone())).into_iter().flatten().flatten();
+                for entry in entries {
+                    if entry.file_name().to_str().is_some_and(|name| name.nfc().eq(wanted.chars())) {
+                        next = matched.join(entry.file_name());
+                        break;
+                    }
+                }
+            }
+        }
+        matched = next;
+    }
+    matched
+}
+
+/// Formats a native path for a reply, FTP paths are separated by `/`.
+fn ftp_path(path: &Path) -> String {
+    if cfg!(windows) {
+        path.to_string_lossy().replace('\\', "/")
+    } else {
+        path.to_string_lossy().into_owned()
+    }
+}
+
+/// Quotes a pathname for a 257 reply as described in RFC 959, doubling any
+/// embedded double quote.
+fn quote_path(path: &Path) -> String {
+    format!("\"{}\"", ftp_path(path).replace('"', "\"\""))
+}
+
 fn prefix_slash(path: &mut PathBuf) {
     if !path.is_absolute() {
         *path = Path::new("/").join(&path);
@@ -59,9 +368,13 @@ use crate::codec::BytesCodec;
 
 type DataReader = SplitStream<Framed<TcpStream, BytesCodec>>;
 type DataWriter = SplitSink<Framed<TcpStream, BytesCodec>, Vec<u8>>;
-type Writer = SplitSink<Framed<TcpStream, FtpCodec>, Answer>;
+type Writer = SplitSink<Framed<Box<dyn ControlStream>, FtpCodec>, Answer>;
+type Reader = SplitStream<Framed<Box<dyn ControlStream>, FtpCodec>>;
 
-use std::ffi::OsString;
+/// What a control connection runs over: plain TCP or TLS.
+trait ControlStream: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send {}
+
+impl<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send> ControlStream for S {}
 
 use std::fs::Metadata;
 #[cfg(windows)]
@@ -78,61 +391,415 @@ fn get_file_info(meta: &Metadata) -> (time::Tm, u64) {
     (time::at(time::Timespec::new(meta.mtime(), 0)), meta.size())
 }
 
-fn get_parent(path: PathBuf) -> Option<PathBuf> {
-    path.parent().map(|p| p.to_path_buf())
+/// Where an upload to `path` is written until it is complete, next to it so
+/// that renaming it over `path` stays on the same file system.
+fn upload_path(path: &Path, id: u64) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{}.part", id));
+    path.with_file_name(name)
+}
+
+/// Opens the file an upload goes to: a new one, or with an `offset` (REST)
+/// the existing one written from that position.
+async fn open_upload(path: &Path, offset: Option<u64>) -> io::Result<File> {
+    match offset {
+        Some(offset) => {
+            let mut file = OpenOptions::new().write(true).create(true).truncate(false).open(path).await?;
+            file.seek(SeekFrom::Start(offset)).await?;
+            Ok(file)
+        }
+        None => File::create(path).await,
+    }
+}
+
+/// Why an upload failed: the data connection broke, or the file couldn't be
+/// written (a full disk...).
+enum UploadError {
+    Transfer(io::Error),
+    Write(io::Error),
+}
+
+/// Runs file system work that may block for a while (a stalled network file
+/// system, long symlink chains) on the blocking pool, so it only holds up the
+/// client waiting for it rather than a runtime worker.
+async fn blocking<T, F>(work: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(work)
+        .await
+        .unwrap_or_else(|error| Err(io::Error::other(error)))
+}
+
+/// Creates `dir` along with its missing parents, returning the directories
+/// created, outermost first. Fails like `create_dir` if `dir` already exists.
+fn create_dir_chain(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut missing: Vec<PathBuf> = dir
+        .ancestors()
+        .take_while(|ancestor| ancestor.symlink_metadata().is_err())
+        .map(Path::to_path_buf)
+        .collect();
+    if missing.is_empty() {
+        return Err(io::ErrorKind::AlreadyExists.into());
+    }
+    missing.reverse();
+    for dir in &missing {
+        create_dir(dir)?;
+    }
+    Ok(missing)
+}
+
+/// Gives a newly created file or directory the `mode` allowed by `umask`.
+#[cfg(unix)]
+fn apply_umask(path: &Path, mode: u32, umask: Option<u32>) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some(umask) = umask {
+        let _ = set_permissions(path, Permissions::from_mode(mode & !umask));
+    }
+}
+
+#[cfg(not(unix))]
+fn apply_umask(_path: &Path, _mode: u32, _umask: Option<u32>) {}
+
+fn write_error_answer(error: &io::Error) -> Answer {
+    match error.kind() {
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded | io::ErrorKind::FileTooLarge => {
+            Answer::new(
+                ResultCode::ExceededStorageAllocation,
+                "Requested file action aborted; exceeded storage allocation",
+            )
+        }
+        io::ErrorKind::PermissionDenied => Answer::new(ResultCode::FileNotFound, "Permission denied"),
+        _ => Answer::new(
+            ResultCode::LocalErrorInProcessing,
+            "Requested action aborted; local error in processing",
+        ),
+    }
+}
+
+/// Binds the first free port of the inclusive `low..=high` range.
+async fn bind_in_range(low: u16, high: u16) -> io::Result<TcpListener> {
+    for port in low..=high {
+        if let Ok(listener) = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port)).await {
+            return Ok(listener);
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AddrInUse, "no free port in the passive range"))
+}
+
+/// Splits a path whose last component is a glob pattern (`*`, `?` or `[...]`)
+/// into the directory to list and the pattern to filter its entries with.
+fn split_glob(path: &Path) -> Option<(PathBuf, Pattern)> {
+    let name = path.file_name()?.to_str()?;
+    if !name.contains(['*', '?', '[']) {
+        return None;
+    }
+    let pattern = Pattern::new(name).ok()?;
+    Some((path.parent().map(Path::to_path_buf).unwrap_or_default(), pattern))
+}
+
+/// State shared by all the connections of a server.
+struct Shared {
+    config: Arc<RwLock<Config>>,
+    failed_logins: Mutex<HashMap<String, LoginFailures>>,
+    next_connection_id: AtomicU64,
+    // Checks the credentials, the users of `config` unless replaced.
+    authenticator: Arc<dyn Authenticator>,
+    // When the recent connections of each address were accepted.
+    connection_times: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+    // The connected clients, by connection ID, for SITE WHO.
+    sessions: Mutex<HashMap<u64, SessionInfo>>,
+    // Upgrades control connections on AUTH TLS, when a certificate is set.
+    tls: Option<TlsAcceptor>,
+    // Tells recent files from old ones in listings.
+    clock: Arc<dyn Clock>,
+}
+
+struct SessionInfo {
+    addr: SocketAddr,
+    user: Option<String>,
+    // Command being run, `None` while waiting for the next one.
+    command: Option<String>,
+    connected_at: Instant,
+    // Tells the connection to close, with the reason, for SITE KICK and
+    // shutdowns.
+    kick: Option<oneshot::Sender<&'static str>>,
+    // Delivers the notices of SITE MSG.
+    messages: mpsc::Sender<String>,
 }
 
-fn get_filename(path: PathBuf) -> Option<OsString> {
-    path.file_name().map(|p| p.to_os_string())
+/// Keeps a connection listed in `Shared::sessions` for as long as it lives.
+struct SessionGuard {
+    shared: Arc<Shared>,
+    id: u64,
+}
+
+impl SessionGuard {
+    /// Registers the connection, the first receiver fires when it gets
+    /// kicked and the second one yields the broadcast messages.
+    fn new(
+        shared: Arc<Shared>,
+        connection: Connection,
+    ) -> (SessionGuard, oneshot::Receiver<&'static str>, mpsc::Receiver<String>) {
+        let (kick, kicked) = oneshot::channel();
+        let (messages, received) = mpsc::channel(MESSAGE_QUEUE_SIZE);
+        let session = SessionInfo {
+            addr: connection.addr,
+            user: None,
+            command: None,
+            connected_at: Instant::now(),
+            kick: Some(kick),
+            messages,
+        };
+        shared.sessions.lock().unwrap().insert(connection.id, session);
+        let guard = SessionGuard {
+            shared,
+            id: connection.id,
+        };
+        (guard, kicked, received)
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.shared.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Shared {
+    fn new(config: Config) -> Shared {
+        let config = Arc::new(RwLock::new(config));
+        Shared {
+            authenticator: config.clone(),
+            config,
+            failed_logins: Mutex::new(HashMap::new()),
+            next_connection_id: AtomicU64::new(1),
+            connection_times: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            tls: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Records a new connection from `ip`, returning false if that address
+    /// opened more than `connection_rate_limit` connections in the window.
+    fn allow_connection(&self, ip: IpAddr) -> bool {
+        let (limit, window) = {
+            let config = self.config.read().unwrap();
+            let window = config.connection_rate_window.unwrap_or(DEFAULT_CONNECTION_RATE_WINDOW);
+            match config.connection_rate_limit {
+                Some(limit) => (limit as usize, Duration::from_secs(window)),
+                None => return true,
+            }
+        };
+        let now = Instant::now();
+        let mut connection_times = self.connection_times.lock().unwrap();
+        // Forget about the connections that left the window, and the addresses
+        // that have none left, so the map doesn't grow forever.
+        connection_times.retain(|_, times| {
+            while times.front().is_some_and(|time| now.duration_since(*time) >= window) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = connection_times.entry(ip).or_default();
+        if times.len() >= limit {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+/// Identifies a control connection in the logs.
+#[derive(Clone, Copy)]
+struct Connection {
+    id: u64,
+    addr: SocketAddr,
+}
+
+impl Display for Connection {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(formatter, "[#{} {}]", self.id, self.addr)
+    }
+}
+
+#[derive(Default)]
+struct LoginFailures {
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+/// Paces a transfer so it doesn't go faster than `rate` bytes per second.
+struct Throttle {
+    rate: Option<u64>,
+    start: Instant,
+}
+
+impl Throttle {
+    fn new(rate: Option<u64>) -> Throttle {
+        Throttle {
+            rate,
+            start: Instant::now(),
+        }
+    }
+
+    /// Waits until moving `transferred` bytes so far is within the rate.
+    async fn wait(&self, transferred: u64) {
+        if let Some(rate) = self.rate {
+            let due = Duration::from_secs_f64(transferred as f64 / rate as f64);
+            let elapsed = self.start.elapsed();
+            if due > elapsed {
+                delay_for(due - elapsed).await;
+            }
+        }
+    }
 }
 
 struct Client {
-    data_port: Option<u16>,
+    connection: Connection,
     data_reader: Option<DataReader>,
     data_writer: Option<DataWriter>,
     cwd: PathBuf,
     name: Option<String>,
+    // What the logged in user is allowed to do.
+    user: Option<UserInfo>,
     server_root: PathBuf,
     transfer_type: TransferType,
+    transfer_mode: TransferMode,
+    file_structure: FileStructure,
     writer: Writer,
+    reader: Reader,
+    // Commands received during a transfer, run once it's over.
+    pending: VecDeque<io::Result<Command>>,
+    // ABOR was received during the current transfer.
+    abort_requested: bool,
     is_admin: bool,
-    config: Config, 
+    // Snapshot of the shared config, refreshed on every USER command.
+    config: Config,
+    shared: Arc<Shared>,
     waiting_password: bool,
+    expected_size: Option<u64>,
+    // Bytes moved over the data connection by the current (or last) transfer.
+    transferred: u64,
+    history: VecDeque<String>,
+    // Offset set by REST for the next RETR/STOR.
+    restart_offset: Option<u64>,
+    /// Where the next RETR stops, set along with `restart_offset` by RANG.
+    restart_end: Option<u64>,
+    // What OPTS set, the HASH algorithm among others.
+    options: Options,
+    umask: Option<u32>,
+    // Idle timeout asked for with SITE IDLE, replacing the configured one.
+    idle_timeout: Option<u64>,
+    // The control connection is encrypted, from the start or after AUTH.
+    tls: bool,
+    // For the summary logged at the end of the session.
+    connected_at: Instant,
+    commands: u64,
+    uploaded: u64,
+    downloaded: u64,
+    // How the session ended, an error when not set.
+    result: Option<&'static str>,
 }
 
 impl Client {
-    fn new(writer: Writer, server_root: PathBuf, config: Config) -> Client {
+    fn new(writer: Writer, reader: Reader, connection: Connection, server_root: PathBuf, shared: Arc<Shared>) -> Client {
+        let config = shared.config.read().unwrap().clone();
+        let umask = config.umask();
+        let options = Options::new(config.hash_algorithm());
         Client {
-            data_port: None,
+            connection,
             data_reader: None,
             data_writer: None,
             cwd: PathBuf::from("/"),
             name: None,
+            user: None,
             server_root,
-            transfer_type: TransferType::Ascii,
+            transfer_type: config.transfer_type(),
+            transfer_mode: TransferMode::Stream,
+            file_structure: FileStructure::File,
             writer,
+            reader,
+            pending: VecDeque::new(),
+            abort_requested: false,
             is_admin: false,
             config,
+            shared,
             waiting_password: false,
+            expected_size: None,
+            transferred: 0,
+            history: VecDeque::with_capacity(HISTORY_SIZE),
+            restart_offset: None,
+            restart_end: None,
+            options,
+            umask,
+            idle_timeout: None,
+            tls: false,
+            connected_at: Instant::now(),
+            commands: 0,
+            uploaded: 0,
+            downloaded: 0,
+            result: None,
         }
     }
 
     async fn handle_cmd(mut self, cmd: Command) -> Result<Self> {
-        println!("Received command: {:?}", cmd);
+        println!("{} Received command: {:?}", self.connection, cmd);
+        self.commands += 1;
+        self.record_history(&cmd);
+        if self.config.is_disabled(cmd.as_ref()) {
+            self.close_data_connection();
+            return self.send(Answer::new(ResultCode::CommandNotImplemented, "Command disabled")).await;
+        }
+        // A REST offset only applies to the transfer it announces: commands that
+        // move around or change the file tree drop it, queries such as SIZE keep it.
+        if matches!(
+            cmd,
+            Command::Cwd(_)
+                | Command::CdUp
+                | Command::Mkd(_)
+                | Command::Rmd(_)
+                | Command::List(_)
+                | Command::Nlst(..)
+                | Command::Site(_)
+                | Command::User(_)
+        ) {
+            self.restart_offset = None;
+            self.restart_end = None;
+        }
 
         if self.is_logged() {
+            // The only check needed to keep a read-only server untouched.
+            if self.config.read_only == Some(true) && cmd.is_mutating() {
+                self.close_data_connection();
+                return self.send(Answer::new(ResultCode::FileNotFound, "Read-only server")).await;
+            }
+            if let Some(ref user) = self.user {
+                if !user.allows(&cmd) {
+                    return self.send(Answer::new(ResultCode::FileNotFound, "Permission denied")).await;
+                }
+            }
+            if let Some((access, path)) = Access::required(&cmd) {
+                let path = self.cwd.join(path.unwrap_or_else(|| Path::new("")));
+                if !self.directory_allows(path, access).await {
+                    self.close_data_connection();
+                    return self.send(Answer::new(ResultCode::FileNotFound, "Permission denied")).await;
+                }
+            }
             match cmd {
                 Command::Cwd(directory) => return Ok(self.cwd(directory).await?),
                 Command::List(path) => return Ok(self.list(path).await?),
+                Command::Nlst(path, recursive) => return self.nlst(path, recursive).await,
                 Command::Pasv => return Ok(self.pasv().await?),
-                Command::Port(port) => {
-                    self.data_port = Some(port);
-                    return Ok(self.send(Answer::new(ResultCode::Ok, &format!("Data port is now {}", port))).await?);
-                },
+                Command::Port(addr) => return self.port(addr).await,
                 Command::Pwd => {
                     let msg = format!("{}", self.cwd.to_str().unwrap_or(""));
                     if !msg.is_empty() {
-                        let message = format!("\"{}\" ", msg);
+                        let message = format!("{} is the current directory", quote_path(&self.cwd));
                         return Ok(self.send(Answer::new(ResultCode::PATHNAMECreated, &message)).await?);
                     } else {
                         return Ok(self.send(Answer::new(ResultCode::FileNotFound, "No such file or directory")).await?);
@@ -147,29 +814,52 @@ impl Client {
                     }
                     return Ok(self.send(Answer::new(ResultCode::Ok, "Done")).await?);
                 },
+                Command::Allo(size) => {
+                    self.expected_size = Some(size);
+                    return self.send(Answer::new(ResultCode::Ok, &format!("Expecting {} bytes", size))).await;
+                },
+                Command::Hash(path) => return self.hash(path).await,
+                Command::Rang(None) => {
+                    return self.send(Answer::new(ResultCode::InvalidParameterOrArgument, "Invalid range")).await;
+                },
+                Command::Rang(Some((start, end))) => {
+                    self.restart_offset = Some(start);
+                    self.restart_end = Some(end);
+                    let message = format!("Next RETR sends bytes {} up to {}, excluded", start, end);
+                    return self.send(Answer::new(ResultCode::RequestedFileActionPendingFurtherInformation, &message)).await;
+                },
+                Command::Rest(offset) => {
+                    self.restart_offset = Some(offset);
+                    self.restart_end = None;
+                    let message = format!("Restarting at {}. Send STOR or RETR", offset);
+                    return self.send(Answer::new(ResultCode::RequestedFileActionPendingFurtherInformation, &message)).await;
+                },
+                Command::Site(args) => return self.site(args).await,
+                Command::Size(path) => return self.size(path).await,
+                Command::Stat => {
+                    let message = self.status();
+                    return self.send(Answer::new(ResultCode::SystemStatus, &message)).await;
+                },
+                Command::Dele(path) => return self.dele(path).await,
                 Command::Mkd(path) => return Ok(self.mkd(path).await?),
-                Command::Rmd(path) => return Ok(self.rmd(path).await?),
+                Command::Rmd(path) => return self.rmd(path, false).await,
                 _ => (),
             }
         } else if self.name.is_some() && self.waiting_password {
             if let Command::Pass(content) = cmd {
-                let mut ok = false;
-                if self.is_admin {
-                    ok = content == self.config.admin.as_ref().unwrap().password;
-                } else {
-                    for user in &self.config.users {
-                        if Some(&user.name) == self.name.as_ref() {
-                            if user.password == content {
-                                ok = true;
-                                break;
-                            }
-                        }
-                    }
+                if self.is_locked() {
+                    return self.send(Answer::new(ResultCode::NotLoggedIn, "Account temporarily locked")).await;
                 }
-                if ok {
+                let name = self.name.clone().unwrap_or_default();
+                let user = self.shared.authenticator.authenticate(&name, &content).await;
+                self.record_login(user.is_some());
+                if let Some(user) = user {
                     self.waiting_password = false;
-                    let name = self.name.clone().unwrap_or(String::new());
-                    self = self.send(Answer::new(ResultCode::UserLoggedIn, &format!("Welcome {}", name))).await?;
+                    self.is_admin = user.permissions.admin;
+                    self.user = Some(user);
+                    self.enter_start_dir(&name).await;
+                    let message = self.login_message(&name, format!("Welcome {}", name)).await;
+                    self = self.send(Answer::new(ResultCode::UserLoggedIn, &message)).await?;
                 } else {
                     self = self.send(Answer::new(ResultCode::NotLoggedIn, "Invalid password")).await?;
                 }
@@ -187,43 +877,43 @@ impl Client {
                         ))
                         .await?;
                 } else {
-                    let mut name = None;
-                    let mut pass_required = true;
-
+                    self.config = self.shared.config.read().unwrap().clone();
                     self.is_admin = false;
-                    if let Some(ref admin) = self.config.admin {
-                        if admin.name == content {
-                            pass_required = admin.password.is_empty() == false;
-                            self.is_admin = true;
-                        }
-                    }
-
-                    // In case the user isn't the admin
-                    if name.is_none() {
-                        for user in &self.config.users {
-                            if user.name == content {
-                                name = Some(content.clone());
-                                pass_required = user.password.is_empty() == false;
-                                break;
-                            }
+                    self.user = None;
+                    let authenticator = self.shared.authenticator.clone();
+                    match authenticator.needs_password(&content).await {
+                        // In case this is an unknown user.
+                        None => {
+                            self = self.send(Answer::new(ResultCode::NotLoggedIn, "Unknown user...")).await?;
                         }
-                    }
-                    // In case this is an unknown user.
-                    if name.is_none() {
-                        self = self.send(Answer::new(ResultCode::NotLoggedIn, "Unknown user...")).await?;
-                    } else {
-                        self.name = name.clone();
-                        if pass_required {
+                        Some(true) => {
+                            self.name = Some(content.clone());
                             self.waiting_password = true;
-                            self = self.send(Answer::new(ResultCode::UserNameOkayNeedPassword, &format!("Login Ok password needed for {}", name.unwrap()))).await?;
-                        } else {
-                            self.waiting_password = false;
-                            self = self.send(Answer::new(ResultCode::UserLoggedIn, &format!("Welcome {}!", content))).await?;
+                            self = self.send(Answer::new(ResultCode::UserNameOkayNeedPassword, &format!("Login Ok password needed for {}", content))).await?;
                         }
+                        Some(false) => match authenticator.authenticate(&content, "").await {
+                            Some(user) => {
+                                self.name = Some(content.clone());
+                                self.waiting_password = false;
+                                self.is_admin = user.permissions.admin;
+                                self.user = Some(user);
+                                self.enter_start_dir(&content).await;
+                                let message = self.login_message(&content, format!("Welcome {}!", content)).await;
+                                self = self.send(Answer::new(ResultCode::UserLoggedIn, &message)).await?;
+                            }
+                            None => {
+                                self = self.send(Answer::new(ResultCode::NotLoggedIn, "Unknown user...")).await?;
+                            }
+                        },
                     }
                 }
             }
             Command::NoOp => self = self.send(Answer::new(ResultCode::Ok, "Doing nothing")).await?,
+            Command::Abor => {
+                // Nothing is running, at most a PASV data connection to drop.
+                self.close_data_connection();
+                self = self.send(Answer::new(ResultCode::ClosingDataConnection, "Abort command successful")).await?;
+            }
             Command::Type(typ) => {
                 self.transfer_type = typ;
                 self = self
@@ -233,9 +923,69 @@ impl Client {
                     ))
                     .await?;
             }
+            Command::Mode(mode) => {
+                if mode == TransferMode::Stream {
+                    self.transfer_mode = mode;
+                    self = self.send(Answer::new(ResultCode::Ok, "Mode set to Stream")).await?;
+                } else {
+                    self = self
+                        .send(Answer::new(
+                            ResultCode::CommandNotImplementedForThatParameter,
+                            "Only stream mode is supported",
+                        ))
+                        .await?;
+                }
+            }
+            Command::Stru(structure) => {
+                if structure == FileStructure::File {
+                    self.file_structure = structure;
+                    self = self.send(Answer::new(ResultCode::Ok, "Structure set to File")).await?;
+                } else {
+                    self = self
+                        .send(Answer::new(
+                            ResultCode::CommandNotImplementedForThatParameter,
+                            "Only file structure is supported",
+                        ))
+                        .await?;
+                }
+            }
             Command::Syst => {
                 self = self.send(Answer::new(ResultCode::Ok, "I won't tell!")).await?;
             }
+            Command::Feat => {
+                let mut message = String::from("Features:\n");
+                if self.shared.tls.is_some() && !self.config.is_disabled("AUTH") {
+                    message.push_str(" AUTH TLS\n PBSZ\n PROT\n");
+                }
+                if !self.config.is_disabled("HASH") {
+                    message.push_str(&format!(" {}\n", self.hash_feature()));
+                }
+                // Each feature is named after the command it's about.
+                for feature in FEATURES {
+                    if !feature.split(' ').next().is_some_and(|command| self.config.is_disabled(command)) {
+                        message.push_str(&format!(" {}\n", feature));
+                    }
+                }
+                if self.config.advertise_utf8 == Some(true) && !self.config.is_disabled("OPTS") {
+                    message.push_str(" UTF8\n");
+                }
+                message.push_str("End");
+                self = self.send(Answer::new(ResultCode::SystemStatus, &message)).await?;
+            }
+            Command::Opts(args) => self = self.opts(args).await?,
+            Command::Help => {
+                let commands: Vec<&str> = COMMANDS
+                    .iter()
+                    .copied()
+                    .filter(|command| !self.config.is_disabled(command))
+                    .collect();
+                let mut message = String::from("The following commands are recognized:\n");
+                for line in commands.chunks(8) {
+                    message.push_str(&format!(" {}\n", line.join(" ")));
+                }
+                message.push_str("Help OK");
+                self = self.send(Answer::new(ResultCode::HelpMessage, &message)).await?;
+            }
             Command::Unknown(s) => {
                 self = self
                     .send(Answer::new(
@@ -245,6 +995,9 @@ impl Client {
                     .await?
             }
             Command::Quit => self = self.quit().await?,
+            Command::Auth(mechanism) => self = self.auth(mechanism).await?,
+            Command::Pbsz(size) => self = self.pbsz(size).await?,
+            Command::Prot(level) => self = self.prot(level).await?,
             _ => {
                 // Not Logged in
                 self = self
@@ -258,159 +1011,742 @@ impl Client {
         Ok(self)
     }
 
-    async fn send(mut self, answer: Answer) -> Result<Self> {
-        self.writer.send(answer).await?;
+    /// AUTH: switches the control connection to TLS. "TLS" and "TLS-C" (RFC
+    /// 4217) are the same, "SSL" is what some older clients send. Like REIN,
+    /// it ends the current login.
+    async fn auth(mut self, mechanism: String) -> Result<Self> {
+        if !["TLS", "TLS-C", "SSL"].contains(&mechanism.as_str()) {
+            let answer = Answer::new(ResultCode::CommandNotImplementedForThatParameter, "Unknown security mechanism");
+            return self.send(answer).await;
+        }
+        let acceptor = match self.shared.tls.clone() {
+            Some(_) if self.tls => {
+                return self.send(Answer::new(ResultCode::BadSequenceOfCommands, "Already using TLS")).await;
+            }
+            Some(acceptor) => acceptor,
+            None => return self.send(Answer::new(ResultCode::CommandNotImplemented, "TLS not available")).await,
+        };
+        self = self.send(Answer::new(ResultCode::SecurityDataExchangeComplete, "Starting TLS")).await?;
+        // The halves are put back together to hand the socket over to TLS,
+        // a stream that's never read nor written standing in meanwhile.
+        let placeholder: Box<dyn ControlStream> = Box::new(std::io::Cursor::new(vec![]));
+        let (writer, reader) = Framed::new(placeholder, FtpCodec).split();
+        let writer = mem::replace(&mut self.writer, writer);
+        let reader = mem::replace(&mut self.reader, reader);
+        let stream = reader
+            .reunite(writer)
+            .map_err(|_| Error::Msg("control connection halves don't match".to_owned()))?
+            .into_inner();
+        let login_timeout = Duration::from_secs(self.config.login_timeout.unwrap_or(DEFAULT_LOGIN_TIMEOUT));
+        let stream = timeout(login_timeout, acceptor.accept(stream))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+        let stream: Box<dyn ControlStream> = Box::new(stream);
+        let (writer, reader) = Framed::new(stream, FtpCodec).split();
+        self.writer = writer;
+        self.reader = reader;
+        self.tls = true;
+        self.name = None;
+        self.user = None;
+        self.is_admin = false;
+        self.waiting_password = false;
         Ok(self)
     }
 
-    async fn pasv(mut self) -> Result<Self> {
-        let port = if let Some(port) = self.data_port {
-            port
-        } else {
-            0
-        };
+    /// PBSZ, which RFC 4217 wants before PROT. Data is streamed, so the only
+    /// buffer size is 0, whatever the client asks for.
+    async fn pbsz(self, size: String) -> Result<Self> {
+        if !self.tls {
+            return self.send(Answer::new(ResultCode::BadSequenceOfCommands, "AUTH first")).await;
+        }
+        if size.parse::<u64>().is_err() {
+            return self.send(Answer::new(ResultCode::InvalidParameterOrArgument, "Invalid buffer size")).await;
+        }
+        self.send(Answer::new(ResultCode::Ok, "PBSZ=0")).await
+    }
 
-        if self.data_writer.is_some() {
-            self = self
-                .send(Answer::new(
-                    ResultCode::DataConnectionAlreadyOpen,
-                    "Already listening...",
-                ))
-                .await?;
-            return Ok(self);
+    /// PROT: only the control connection is encrypted, so data connections
+    /// can only be left clear.
+    async fn prot(self, level: String) -> Result<Self> {
+        if !self.tls {
+            return self.send(Answer::new(ResultCode::BadSequenceOfCommands, "AUTH first")).await;
         }
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
-        let mut listener = TcpListener::bind(addr).await?;
-        let port = listener.local_addr()?.port();
-        self = self
-            .send(Answer::new(
-                ResultCode::EnteringPassiveMode,
-                &format!("127,0,0,1,{},{}", port >> 8, port & 0xFF),
-            ))
-            .await?;
-        println!("Waiting clients on port {}...", port);
+        let answer = match level.as_str() {
+            "C" => Answer::new(ResultCode::Ok, "Protection level set to Clear"),
+            "S" | "E" | "P" => {
+                Answer::new(ResultCode::ProtectionLevelNotSupported, "Data connections can't be protected")
+            }
+            _ => Answer::new(ResultCode::CommandNotImplementedForThatParameter, "Unknown protection level"),
+        };
+        self.send(answer).await
+    }
 
-        let (socket, addr) = listener.accept().await?;
-        println!("Address: {}", addr);
-        let (writer, reader) = Framed::new(socket, BytesCodec).split();
-        self.data_writer = Some(writer);
-        self.data_reader = Some(reader);
+    fn is_locked(&self) -> bool {
+        let name = self.name.as_deref().unwrap_or_default();
+        let failures = self.shared.failed_logins.lock().unwrap();
+        failures
+            .get(name)
+            .and_then(|failures| failures.locked_until)
+            .is_some_and(|until| until > Instant::now())
+    }
 
-        Ok(self)
+    /// Counts failed logins per user, locking the account for `lockout_duration`
+    /// seconds once `max_login_failures` is reached.
+    fn record_login(&self, success: bool) {
+        let name = self.name.clone().unwrap_or_default();
+        let mut failed_logins = self.shared.failed_logins.lock().unwrap();
+        if success {
+            failed_logins.remove(&name);
+            return;
+        }
+        let failures = failed_logins.entry(name).or_default();
+        failures.count += 1;
+        if failures.count >= self.config.max_login_failures.unwrap_or(DEFAULT_MAX_LOGIN_FAILURES) {
+            let duration = self.config.lockout_duration.unwrap_or(DEFAULT_LOCKOUT_DURATION);
+            failures.count = 0;
+            failures.locked_until = Some(Instant::now() + Duration::from_secs(duration));
+        }
     }
 
-    async fn cwd(mut self, directory: PathBuf) -> Result<Self> {
-        let path = self.cwd.join(&directory);
-        let (new_self, res) = self.complete_path(path);
-        self = new_self;
-        if let Ok(dir) = res {
-            let (new_self, res) = self.strip_prefix(dir);
-            self = new_self;
-            if let Ok(prefix) = res {
-                self.cwd = prefix.to_path_buf();
-                self = self
-                    .send(Answer::new(
-                        ResultCode::RequestedFileActionOkay,
-                        &format!("Directory changed to \"{}\"", directory.display()),
-                    ))
-                    .await?;
-                return Ok(self);
-            }
+    /// Publishes the user and the running command for SITE WHO.
+    fn update_session(&self, command: Option<&str>) {
+        if let Some(session) = self.shared.sessions.lock().unwrap().get_mut(&self.connection.id) {
+            session.user = if self.is_logged() { self.name.clone() } else { None };
+            session.command = command.map(str::to_owned);
         }
-        self = self
-            .send(Answer::new(
-                ResultCode::FileNotFound,
-                "No such file or directory",
-            ))
-            .await?;
-        Ok(self)
     }
 
-    fn complete_path(self, path: PathBuf) -> (Self, result::Result<PathBuf, io::Error>) {
-        let directory = self.server_root.join(if path.has_root() {
-            path.iter().skip(1).collect()
-        } else {
-            path
-        });
+    /// Whether the directory rules allow `access` to `path`, a path of the
+    /// client. It is checked where it really leads, symlinks included; paths
+    /// that can't be resolved are left for the command to reject.
+    async fn directory_allows(&self, path: PathBuf, access: Access) -> bool {
+        if self.config.directory_rules.is_none() && self.config.dropbox_dirs.is_none() {
+            return true;
+        }
+        let server_root = self.server_root.clone();
+        match blocking(move || resolve_path_allow_missing(&server_root, path)).await {
+            Ok(path) => path
+                .strip_prefix(&self.server_root)
+                .is_ok_and(|path| directory_allows(&self.config, path, access)),
+            Err(_) => true,
+        }
+    }
 
-        let dir = directory.canonicalize();
-        if let Ok(ref dir) = dir {
-            if !dir.starts_with(&self.server_root) {
-                return (self, Err(io::ErrorKind::PermissionDenied.into()));
+    /// Moves a session that just logged in to its start directory: the
+    /// `default_dir` of their account or of the server, under the home of the
+    /// user when they have one. It falls back to the home, then to "/", when
+    /// that isn't a directory under the server root.
+    async fn enter_start_dir(&mut self, name: &str) {
+        let home = self.user.as_ref().and_then(|user| user.home.as_ref()).map(|home| Path::new("/").join(home));
+        let default_dir = account(&self.config, name)
+            .and_then(|account| account.default_dir.clone())
+            .or_else(|| self.config.default_dir.clone());
+        let base = home.clone().unwrap_or_else(|| PathBuf::from("/"));
+        let candidates = default_dir.map(|dir| base.join(dir.trim_start_matches('/'))).into_iter().chain(home);
+        self.cwd = PathBuf::from("/");
+        for dir in candidates {
+            let server_root = self.server_root.clone();
+            let candidate = dir.clone();
+            match blocking(move || resolve_path(&server_root, candidate)).await {
+                Ok(ref resolved) if is_dir(resolved).await => {
+                    self.cwd = dir;
+                    break;
+                }
+                _ => println!("{} Start directory {} isn't usable", self.connection, dir.display()),
             }
         }
-        (self, dir)
     }
 
-    fn strip_prefix(self, dir: PathBuf) -> (Self, result::Result<PathBuf, StripPrefixError>) {
-        let res = dir.strip_prefix(&self.server_root).map(|p| p.to_path_buf());
-        (self, res)
+    /// The reply to a successful login: the MOTD file, when there is one,
+    /// followed by `welcome`.
+    async fn login_message(&self, name: &str, welcome: String) -> String {
+        let motd = match self.config.motd {
+            Some(ref motd) => {
+                let path = self.server_root.join(motd);
+                blocking(move || std::fs::read_to_string(path)).await.ok()
+            }
+            None => None,
+        };
+        match motd {
+            Some(ref motd) if !motd.trim().is_empty() => format!("{}\n{}", motd.trim_end().replace("%u", name), welcome),
+            _ => welcome,
+        }
     }
 
-    async fn quit(mut self) -> Result<Self> {
-        if self.data_writer.is_some() {
-            unimplemented!();
+    /// Lists the connected clients, one per line.
+    fn who(&self) -> String {
+        let sessions = self.shared.sessions.lock().unwrap();
+        let mut ids: Vec<&u64> = sessions.keys().collect();
+        ids.sort();
+        let mut message = String::from("Connected users:\n");
+        for id in ids {
+            let session = &sessions[id];
+            message.push_str(&format!(
+                " #{} {} {} {} {}s\n",
+                id,
+                session.user.as_deref().unwrap_or("-"),
+                session.addr,
+                session.command.as_deref().unwrap_or("idle"),
+                session.connected_at.elapsed().as_secs()
+            ));
+        }
+        message.push_str("End of list");
+        message
+    }
+
+    fn record_history(&mut self, cmd: &Command) {
+        if self.history.len() == HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(format!("{:?}", cmd));
+    }
+
+    async fn opts(mut self, args: String) -> Result<Self> {
+        let answer = opts::dispatch(&mut self.options, &args);
+        self.send(answer).await
+    }
+
+    // The FEAT line listing the HASH algorithms, the selected one marked with `*`.
+    fn hash_feature(&self) -> String {
+        let mut feature = String::from("HASH ");
+        for algorithm in HashAlgorithm::ALL.iter() {
+            feature.push_str(algorithm.name());
+            if *algorithm == self.options.hash_algorithm {
+                feature.push('*');
+            }
+            feature.push(';');
+        }
+        feature
+    }
+
+    async fn site(self, args: String) -> Result<Self> {
+        let mut args = args.splitn(2, ' ');
+        let subcommand = args.next().unwrap_or_default().to_uppercase();
+        let command = match SITE_COMMANDS.iter().find(|command| command.name == subcommand) {
+            Some(command) => command,
+            None => {
+                return self
+                    .send(Answer::new(
+                        ResultCode::UnknownCommand,
+                        &format!("\"SITE {}\": Not implemented", subcommand),
+                    ))
+                    .await
+            }
+        };
+        if command.admin_only && !self.is_admin {
+            return self.send(Answer::new(ResultCode::FileNotFound, "Permission denied")).await;
+        }
+        (command.handler)(self, args.next().map(str::to_owned)).await
+    }
+
+    async fn site_help(self) -> Result<Self> {
+        let mut message = String::from("The following SITE commands are recognized:\n");
+        for command in SITE_COMMANDS {
+            if self.is_admin || !command.admin_only {
+                message.push_str(&format!(" {:<8}{}\n", command.name, command.description));
+            }
+        }
+        message.push_str("Help OK");
+        self.send(Answer::new(ResultCode::HelpMessage, &message)).await
+    }
+
+    async fn site_history(self) -> Result<Self> {
+        let mut message = String::from("Command history:\n");
+        for entry in &self.history {
+            message.push_str(&format!(" {}\n", entry));
+        }
+        message.push_str("End of history");
+        self.send(Answer::new(ResultCode::Ok, &message)).await
+    }
+
+    async fn site_reload(self) -> Result<Self> {
+        let path = self.server_root.join(CONFIG_FILE);
+        let res = blocking(move || {
+            Ok(if path.is_file() {
+                Config::new(path).and_then(|config| config.validate().map(|_| config).map_err(Error::Msg))
+            } else {
+                Err(Error::Msg("config file not found".to_owned()))
+            })
+        })
+        .await
+        .unwrap_or_else(|error| Err(error.into()));
+        let answer = match res {
+            Ok(config) => {
+                *self.shared.config.write().unwrap() = config;
+                Answer::new(ResultCode::Ok, "Config reloaded")
+            }
+            Err(error) => Answer::new(
+                ResultCode::LocalErrorInProcessing,
+                &format!("Couldn't reload config: {}", error.to_string().replace('\n', " ")),
+            ),
+        };
+        self.send(answer).await
+    }
+
+    async fn site_hash(mut self, args: Option<String>) -> Result<Self> {
+        let answer = opts::hash(&mut self.options, args.as_deref());
+        self.send(answer).await
+    }
+
+    async fn site_who(self) -> Result<Self> {
+        let message = self.who();
+        self.send(Answer::new(ResultCode::Ok, &message)).await
+    }
+
+    async fn site_kick(self, args: Option<String>) -> Result<Self> {
+        let id = args.and_then(|id| id.trim().trim_start_matches('#').parse::<u64>().ok());
+        let kick = id.and_then(|id| {
+            let mut sessions = self.shared.sessions.lock().unwrap();
+            sessions.get_mut(&id).and_then(|session| session.kick.take())
+        });
+        let answer = if kick.is_some_and(|kick| kick.send("Kicked by administrator").is_ok()) {
+            Answer::new(ResultCode::Ok, "Kicked")
         } else {
-            self = self
+            Answer::new(ResultCode::FileNotFound, "No such session")
+        };
+        self.send(answer).await
+    }
+
+    async fn site_msg(self, args: Option<String>) -> Result<Self> {
+        let message = match args.as_deref().map(str::trim) {
+            Some(message) if !message.is_empty() => message.replace(['\r', '\n'], " "),
+            _ => {
+                return self
+                    .send(Answer::new(ResultCode::InvalidParameterOrArgument, "Usage: SITE MSG <text>"))
+                    .await
+            }
+        };
+        let sent = self
+            .shared
+            .sessions
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .filter(|(id, _)| **id != self.connection.id)
+            .filter_map(|(_, session)| session.messages.try_send(message.clone()).ok())
+            .count();
+        self.send(Answer::new(ResultCode::Ok, &format!("Message sent to {} clients", sent))).await
+    }
+
+    async fn site_umask(mut self, args: Option<String>) -> Result<Self> {
+        if !cfg!(unix) {
+            return self
                 .send(Answer::new(
-                    ResultCode::ServiceClosingControlConnection,
-                    "Closing connection...",
+                    ResultCode::CommandNotImplementedForThatParameter,
+                    "SITE UMASK isn't supported on this platform",
                 ))
-                .await?;
-            self.writer.close().await?;
+                .await;
+        }
+        let answer = match args.as_deref().map(str::trim) {
+            Some(umask) => match u32::from_str_radix(umask, 8) {
+                Ok(umask) if umask <= 0o777 => {
+                    self.umask = Some(umask);
+                    Answer::new(ResultCode::Ok, &format!("UMASK set to {:03o}", umask))
+                }
+                _ => Answer::new(ResultCode::InvalidParameterOrArgument, "Invalid umask"),
+            },
+            None => match self.umask {
+                Some(umask) => Answer::new(ResultCode::Ok, &format!("Current UMASK is {:03o}", umask)),
+                None => Answer::new(ResultCode::Ok, "No UMASK set"),
+            },
+        };
+        self.send(answer).await
+    }
+
+    async fn site_idle(mut self, args: Option<String>) -> Result<Self> {
+        let max = self.config.max_idle_timeout.unwrap_or(DEFAULT_MAX_IDLE_TIMEOUT);
+        let answer = match args.as_deref().map(str::trim) {
+            // Anyone can look, only admins can keep a session open longer.
+            Some(_) if !self.is_admin => Answer::new(ResultCode::FileNotFound, "Permission denied"),
+            Some(seconds) => match seconds.parse::<u64>() {
+                Ok(seconds) if seconds > 0 && seconds <= max => {
+                    self.idle_timeout = Some(seconds);
+                    Answer::new(ResultCode::Ok, &format!("Maximum IDLE time set to {} seconds", seconds))
+                }
+                _ => Answer::new(
+                    ResultCode::InvalidParameterOrArgument,
+                    &format!("IDLE time must be between 1 and {} seconds", max),
+                ),
+            },
+            None => Answer::new(
+                ResultCode::Ok,
+                &format!("Current IDLE time limit is {} seconds; max {}", self.idle_timeout(), max),
+            ),
+        };
+        self.send(answer).await
+    }
+
+    async fn site_quota(self, args: Option<String>) -> Result<Self> {
+        let name = args.as_deref().map(str::trim).filter(|name| !name.is_empty());
+        let user = match name {
+            Some(_) if !self.is_admin => {
+                return self.send(Answer::new(ResultCode::FileNotFound, "Permission denied")).await;
+            }
+            Some(name) => match account(&self.config, name) {
+                Some(_) => config_user_info(&self.config, name),
+                None => return self.send(Answer::new(ResultCode::FileNotFound, "No such user")).await,
+            },
+            None => self
+                .user
+                .clone()
+                .unwrap_or_else(|| config_user_info(&self.config, self.name.as_deref().unwrap_or_default())),
+        };
+        let server_root = self.server_root.clone();
+        let usage = match (user.quota, user.home) {
+            (Some(quota), Some(home)) => blocking(move || home_usage(&server_root, home)).await.map(|usage| (quota, usage)),
+            _ => Ok((0, None)),
+        };
+        let answer = match usage {
+            Ok((quota, Some((_, usage)))) => Answer::new(ResultCode::Ok, &format!("Usage: {} / {} bytes", usage, quota)),
+            Ok((_, None)) => Answer::new(ResultCode::Ok, "No quota configured"),
+            Err(_) => Answer::new(ResultCode::LocalErrorInProcessing, "Couldn't compute the usage"),
+        };
+        self.send(answer).await
+    }
+
+    async fn site_rmdr(self, args: Option<String>) -> Result<Self> {
+        match args {
+            Some(path) => self.rmd(PathBuf::from(path), true).await,
+            None => self.send(Answer::new(ResultCode::InvalidParameterOrArgument, "Missing directory")).await,
         }
+    }
+
+    async fn send(mut self, answer: Answer) -> Result<Self> {
+        self.writer.send(answer).await?;
         Ok(self)
     }
 
-    async fn mkd(mut self, path: PathBuf) -> Result<Self> {
-        let path = self.cwd.join(&path);
-        let parent = get_parent(path.clone());
-        if let Some(parent) = parent {
-            let parent = parent.to_path_buf();
-            let (new_self, res) = self.complete_path(parent);
-            self = new_self;
-            if let Ok(mut dir) = res {
-                if dir.is_dir() {
-                    let filename = get_filename(path);
-                    if let Some(filename) = filename {
-                        dir.push(filename);
-                        if create_dir(dir).is_ok() {
-                            self = self
-                                .send(Answer::new(
-                                    ResultCode::PATHNAMECreated,
-                                    "Folder successfully created!",
-                                ))
-                                .await?;
-                            return Ok(self);
+    /// Sends a 421 with `message` and closes the control connection.
+    async fn close_control(self, message: &str) -> Result<()> {
+        let mut client = self.send(Answer::new(ResultCode::ServiceNotAvailable, message)).await?;
+        client.writer.close().await?;
+        Ok(())
+    }
+
+    async fn pasv(mut self) -> Result<Self> {
+        if self.data_writer.is_some() {
+            self = self
+                .send(Answer::new(
+                    ResultCode::DataConnectionAlreadyOpen,
+                    "Already listening...",
+                ))
+                .await?;
+            return Ok(self);
+        }
+        let listener = match self.config.passive_port_range {
+            Some((low, high)) => bind_in_range(low, high).await,
+            None => TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0)).await,
+        };
+        let mut listener = match listener {
+            Ok(listener) => listener,
+            Err(err) => return self.data_connection_failed(err).await,
+        };
+        let port = match listener.local_addr() {
+            Ok(addr) => addr.port(),
+            Err(err) => return self.data_connection_failed(err).await,
+        };
+        // If the reply can't be sent the control connection is gone: the listener
+        // is dropped on return and the port released along with it.
+        self = self
+            .send(Answer::new(
+                ResultCode::EnteringPassiveMode,
+                &format!("127,0,0,1,{},{}", port >> 8, port & 0xFF),
+            ))
+            .await?;
+        println!("{} Waiting clients on port {}...", self.connection, port);
+
+        // The control connection is still watched, so the port is released
+        // as soon as the client goes away or sends ABOR.
+        let accept = timeout(DATA_CONNECTION_TIMEOUT, listener.accept());
+        tokio::pin!(accept);
+        let accepted = loop {
+            tokio::select! {
+                accepted = &mut accept => break accepted,
+                cmd = self.reader.next() => {
+                    match cmd {
+                        Some(cmd) => self.transfer_command(cmd).await?,
+                        None => {
+                            let error = io::Error::new(io::ErrorKind::ConnectionAborted, "control connection closed");
+                            return Err(error.into());
                         }
                     }
+                    if mem::take(&mut self.abort_requested) {
+                        return self
+                            .send(Answer::new(ResultCode::ClosingDataConnection, "Abort command successful"))
+                            .await;
+                    }
                 }
             }
+        };
+        let (socket, addr) = match accepted {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(err)) => return self.data_connection_failed(err).await,
+            Err(_) => {
+                let err = io::Error::new(io::ErrorKind::TimedOut, "no data connection was made");
+                return self.data_connection_failed(err).await;
+            }
+        };
+        // Anybody could connect to the port meanwhile and steal or inject the
+        // data, only the client itself may. On a dual-stack listener an IPv4
+        // client's control address is IPv4-mapped, the data one isn't.
+        if addr.ip().to_canonical() != self.connection.addr.ip().to_canonical() {
+            drop(socket);
+            let err = io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("data connection from foreign address {}", addr),
+            );
+            return self.data_connection_failed(err).await;
+        }
+        println!("{} Data connection from {}", self.connection, addr);
+        self.open_data_connection(socket);
+        Ok(self)
+    }
+
+    /// Active mode: connects to the address the client gave. It may well be
+    /// unreachable, so the attempt is bounded by `active_connect_timeout`.
+    async fn port(mut self, addr: SocketAddrV4) -> Result<Self> {
+        if self.data_writer.is_some() {
+            return self.send(Answer::new(ResultCode::DataConnectionAlreadyOpen, "Already connected...")).await;
+        }
+        // Connecting to another host for the client is the FTP bounce attack.
+        if IpAddr::V4(*addr.ip()) != self.connection.addr.ip().to_canonical() {
+            println!("{} Refused PORT to foreign address {}", self.connection, addr);
+            return self
+                .send(Answer::new(ResultCode::InvalidParameterOrArgument, "PORT must give the client's own address"))
+                .await;
+        }
+        let connect_timeout = self.config.active_connect_timeout.unwrap_or(DEFAULT_ACTIVE_CONNECT_TIMEOUT);
+        let socket = match timeout(Duration::from_secs(connect_timeout), TcpStream::connect(addr)).await {
+            Ok(Ok(socket)) => socket,
+            Ok(Err(err)) => return self.data_connection_failed(err).await,
+            Err(_) => {
+                let err = io::Error::new(io::ErrorKind::TimedOut, "connecting to the client timed out");
+                return self.data_connection_failed(err).await;
+            }
+        };
+        println!("{} Data connection to {}", self.connection, addr);
+        self.open_data_connection(socket);
+        self.send(Answer::new(ResultCode::Ok, "PORT command successful")).await
+    }
+
+    fn open_data_connection(&mut self, socket: TcpStream) {
+        if let Err(error) = SocketOptions::data(&self.config).apply(&socket) {
+            println!("{} Couldn't set data socket options: {}", self.connection, error);
+        }
+        let (writer, reader) = Framed::with_capacity(socket, BytesCodec, self.data_buffer_size()).split();
+        self.data_writer = Some(writer);
+        self.data_reader = Some(reader);
+    }
+
+    async fn data_connection_failed(mut self, err: io::Error) -> Result<Self> {
+        println!("{} Couldn't open data connection: {}", self.connection, err);
+        self.data_reader = None;
+        self.data_writer = None;
+        self.send(Answer::new(
+            ResultCode::CantOpenDataConnection,
+            "Can't open data connection",
+        ))
+        .await
+    }
+
+    async fn cwd(mut self, directory: PathBuf) -> Result<Self> {
+        let path = self.cwd.join(&directory);
+        let (new_self, res) = self.complete_path(path).await;
+        self = new_self;
+        if let Ok(dir) = res {
+            // Changing into a file would break every command relying on cwd.
+            if !is_dir(&dir).await {
+                return self.send(Answer::new(ResultCode::FileNotFound, "Not a directory")).await;
+            }
+            let (new_self, res) = self.strip_prefix(dir);
+            self = new_self;
+            if let Ok(prefix) = res {
+                // Kept absolute, PWD has to reply with a full path.
+                self.cwd = Path::new("/").join(prefix);
+                self = self
+                    .send(Answer::new(
+                        ResultCode::RequestedFileActionOkay,
+                        &format!("Directory changed to \"{}\"", directory.display()),
+                    ))
+                    .await?;
+                return Ok(self);
+            }
         }
         self = self
             .send(Answer::new(
                 ResultCode::FileNotFound,
-                "Couldn't create folder",
+                "No such file or directory",
             ))
             .await?;
         Ok(self)
     }
 
-    async fn rmd(mut self, directory: PathBuf) -> Result<Self> {
-        let path = self.cwd.join(&directory);
-        let (new_self, res) = self.complete_path(path);
+    // Resolving a path walks the file system, which can take a while on a
+    // network file system: it runs on the blocking pool, see `blocking`.
+    async fn complete_path(self, path: PathBuf) -> (Self, result::Result<PathBuf, io::Error>) {
+        let server_root = self.server_root.clone();
+        let utf8 = self.options.utf8;
+        let res = blocking(move || resolve_path(&server_root, client_path(&server_root, &path, utf8))).await;
+        (self, res)
+    }
+
+    async fn complete_path_allow_missing(self, path: PathBuf) -> (Self, io::Result<PathBuf>) {
+        let server_root = self.server_root.clone();
+        let utf8 = self.options.utf8;
+        let res =
+            blocking(move || resolve_path_allow_missing(&server_root, client_path(&server_root, &path, utf8))).await;
+        (self, res)
+    }
+
+    /// Resolves the path of something to create: only the last component may
+    /// be missing, its parent has to be an existing directory.
+    async fn resolve_for_create(self, path: PathBuf) -> (Self, io::Result<PathBuf>) {
+        let server_root = self.server_root.clone();
+        let utf8 = self.options.utf8;
+        let res = blocking(move || {
+            let path = resolve_path_allow_missing(&server_root, client_path(&server_root, &path, utf8))?;
+            match path.parent() {
+                Some(parent) if parent.is_dir() => Ok(path),
+                _ => Err(io::ErrorKind::NotFound.into()),
+            }
+        })
+        .await;
+        (self, res)
+    }
+
+    fn strip_prefix(self, dir: PathBuf) -> (Self, result::Result<PathBuf, StripPrefixError>) {
+        let res = dir.strip_prefix(&self.server_root).map(|p| p.to_path_buf());
+        (self, res)
+    }
+
+    async fn quit(mut self) -> Result<Self> {
+        // Commands run one at a time, an open data connection is an unused one.
+        self.close_data_connection();
+        self = self
+            .send(Answer::new(
+                ResultCode::ServiceClosingControlConnection,
+                "Closing connection...",
+            ))
+            .await?;
+        self.writer.close().await?;
+        Ok(self)
+    }
+
+    async fn mkd(mut self, path: PathBuf) -> Result<Self> {
+        let path = self.cwd.join(&path);
+        let recursive = self.config.recursive_mkd == Some(true);
+        let (new_self, res) = if recursive {
+            self.complete_path_allow_missing(path.clone()).await
+        } else {
+            self.resolve_for_create(path.clone()).await
+        };
         self = new_self;
         if let Ok(dir) = res {
-            if remove_dir_all(dir).is_ok() {
+            let created = if recursive {
+                create_dir_chain(&dir)
+            } else {
+                create_dir(&dir).map(|_| vec![dir])
+            };
+            if let Ok(created) = created {
+                for dir in created {
+                    apply_umask(&dir, 0o777, self.umask);
+                }
                 self = self
                     .send(Answer::new(
-                        ResultCode::RequestedFileActionOkay,
-                        "successfully removed",
+                        ResultCode::PATHNAMECreated,
+                        &format!("{} created", quote_path(&path)),
                     ))
                     .await?;
                 return Ok(self);
             }
         }
+        self = self
+            .send(Answer::new(
+                ResultCode::FileNotFound,
+                "Couldn't create folder",
+            ))
+            .await?;
+        Ok(self)
+    }
+
+    async fn dele(mut self, path: PathBuf) -> Result<Self> {
+        let path = self.cwd.join(path);
+        let (new_self, res) = self.complete_path(path).await;
+        self = new_self;
+        if let Ok(file) = res {
+            let allowed = self.is_admin || file != self.server_root.join(CONFIG_FILE);
+            if allowed && is_file(&file).await && remove_file(&file).is_ok() {
+                return self.send(Answer::new(ResultCode::RequestedFileActionOkay, "File deleted")).await;
+            }
+        }
+        self.send(Answer::new(ResultCode::FileNotFound, "Couldn't delete file")).await
+    }
+
+    async fn hash(mut self, path: PathBuf) -> Result<Self> {
+        let path = self.cwd.join(path);
+        let (new_self, res) = self.complete_path(path.clone()).await;
+        self = new_self;
+        if let Ok(file) = res {
+            if is_file(&file).await && (self.is_admin || file != self.server_root.join(CONFIG_FILE)) {
+                if let Ok((size, hash)) = hash_file(&file, self.options.hash_algorithm).await {
+                    let message = format!("{} 0-{} {} {}", self.options.hash_algorithm.name(), size, hash, path.display());
+                    return self.send(Answer::new(ResultCode::FileStatus, &message)).await;
+                }
+            }
+        }
+        self.send(Answer::new(ResultCode::FileNotFound, "No such file or directory")).await
+    }
+
+    async fn size(mut self, path: PathBuf) -> Result<Self> {
+        let path = self.cwd.join(path);
+        let (new_self, res) = self.complete_path(path).await;
+        self = new_self;
+        if let Ok(file) = res {
+            if self.is_admin || file != self.server_root.join(CONFIG_FILE) {
+                if let Ok(metadata) = metadata(&file).await {
+                    if metadata.is_file() {
+                        // RETR sends the bytes as they are whatever the TYPE, so
+                        // does SIZE: REST offsets computed from it stay right.
+                        return self.send(Answer::new(ResultCode::FileStatus, &metadata.len().to_string())).await;
+                    }
+                }
+            }
+        }
+        self.send(Answer::new(ResultCode::FileNotFound, "No such file or directory")).await
+    }
+
+    /// Removes an empty directory, or a whole tree when `recursive` is set
+    /// (SITE RMDR).
+    async fn rmd(mut self, directory: PathBuf, recursive: bool) -> Result<Self> {
+        let path = self.cwd.join(&directory);
+        let (new_self, res) = self.complete_path(path).await;
+        self = new_self;
+        if let Ok(dir) = res {
+            if dir != self.server_root {
+                let res = if recursive { remove_dir_all(dir) } else { remove_dir(dir) };
+                match res {
+                    Ok(()) => {
+                        self = self
+                            .send(Answer::new(
+                                ResultCode::RequestedFileActionOkay,
+                                "successfully removed",
+                            ))
+                            .await?;
+                        return Ok(self);
+                    }
+                    Err(ref error) if error.kind() == io::ErrorKind::DirectoryNotEmpty => {
+                        self = self
+                            .send(Answer::new(
+                                ResultCode::FileNotFound,
+                                "Directory not empty",
+                            ))
+                            .await?;
+                        return Ok(self);
+                    }
+                    Err(_) => (),
+                }
+            }
+        }
         self = self
             .send(Answer::new(
                 ResultCode::FileNotFound,
@@ -420,13 +1756,45 @@ impl Client {
         Ok(self)
     }
 
-    async fn list(mut self, path: Option<PathBuf>) -> Result<Self> {
+    async fn list(self, path: Option<PathBuf>) -> Result<Self> {
+        self.send_listing(path, add_file_info, false).await
+    }
+
+    async fn nlst(self, path: Option<PathBuf>, recursive: bool) -> Result<Self> {
+        self.send_listing(path, add_file_name, recursive).await
+    }
+
+    /// Listings are text and always sent with CRLF line endings, whatever
+    /// TYPE the client selected. A recursive listing gives the paths relative
+    /// to the listed directory instead of using `format`.
+    async fn send_listing(
+        mut self,
+        path: Option<PathBuf>,
+        format: fn(PathBuf, &dyn Clock, &mut Vec<u8>),
+        recursive: bool,
+    ) -> Result<Self> {
         if self.data_writer.is_some() {
             let path = self.cwd.join(path.unwrap_or_default());
-            let directory = PathBuf::from(&path);
-
-            let (new_self, res) = self.complete_path(directory);
+            let (new_self, literal) = self.complete_path(path.clone()).await;
             self = new_self;
+            let (res, pattern) = match (literal, split_glob(&path)) {
+                // A name that only looks like a pattern ("a[1].txt") is
+                // listed as is when it exists.
+                (Err(_), Some((parent, pattern))) => {
+                    let (new_self, res) = self.complete_path(parent).await;
+                    self = new_self;
+                    (res, Some(pattern))
+                }
+                (literal, _) => (literal, None),
+            };
+            let hidden = |path: &PathBuf| !self.is_admin && *path == self.server_root.join(CONFIG_FILE);
+            // A pattern needs a directory to match in, and a file is only
+            // listed on its own.
+            let res = match res {
+                Ok(ref path) if hidden(path) => Err(io::ErrorKind::NotFound.into()),
+                Ok(path) if pattern.is_some() && !is_dir(&path).await => Err(io::ErrorKind::NotFound.into()),
+                res => res,
+            };
             if let Ok(path) = res {
                 self = self
                     .send(Answer::new(
@@ -434,51 +1802,129 @@ impl Client {
                         "Starting to list directory...",
                     ))
                     .await?;
+                self.transferred = 0;
 
                 let mut out = vec![];
-                if path.is_dir() {
-                    if let Ok(dir) = read_dir(path) {
-                        for entry in dir {
-                            if let Ok(entry) = entry {
-                                if self.is_admin || entry.path() != self.server_root.join(CONFIG_FILE) {
-                                    add_file_info(entry.path(), &mut out);
+                let clock = self.shared.clock.clone();
+                let mut entries_sent = 0;
+                let mut truncated = None;
+                if is_dir(&path).await {
+                    if let Ok(dir) = blocking(move || read_dir(path)).await {
+                        let max_depth = self.config.max_list_depth.unwrap_or(DEFAULT_MAX_LIST_DEPTH);
+                        // Subdirectories left to list, with their path relative
+                        // to the listed directory and their depth.
+                        let mut subdirs = VecDeque::new();
+                        let mut listing = Some((dir, PathBuf::new(), 0));
+                        'listing: while let Some((mut entries, prefix, depth)) = listing.take() {
+                            // Entries are read, and stat'ed by `format`, a batch at
+                            // a time on the blocking pool, and sent as they come so
+                            // huge directories never end up in memory all at once.
+                            loop {
+                                let res = blocking(move || {
+                                    let batch: Vec<_> = entries.by_ref().take(LISTING_BATCH_SIZE).flatten().collect();
+                                    Ok((entries, batch))
+                                });
+                                let batch = match res.await {
+                                    Ok((rest, batch)) if !batch.is_empty() => {
+                                        entries = rest;
+                                        batch
+                                    }
+                                    _ => break,
+                                };
+                                let mut to_format = vec![];
+                                for entry in batch {
+                                    // The pattern only filters the listed directory itself.
+                                    let matches = depth > 0
+                                        || pattern
+                                            .as_ref()
+                                            .is_none_or(|pattern| pattern.matches(&entry.file_name().to_string_lossy()));
+                                    if !matches || (!self.is_admin && entry.path() == self.server_root.join(CONFIG_FILE)) {
+                                        continue;
+                                    }
+                                    if let Some(max) = self.config.max_list_entries.filter(|max| entries_sent >= *max) {
+                                        truncated = Some(max);
+                                        break;
+                                    }
+                                    entries_sent += 1;
+                                    let relative = prefix.join(entry.file_name());
+                                    if recursive {
+                                        out.extend(format!("{}\r\n", relative.to_string_lossy()).as_bytes());
+                                        // Symlinks aren't followed, they could lead out of the root.
+                                        // Dropboxes and directory rules apply to the
+                                        // subdirectories too.
+                                        if depth < max_depth
+                                            && entry.file_type().is_ok_and(|typ| typ.is_dir())
+                                            && entry.path().strip_prefix(&self.server_root).is_ok_and(|path| {
+                                                directory_allows(&self.config, path, Access::List)
+                                            })
+                                        {
+                                            subdirs.push_back((entry.path(), relative, depth + 1));
+                                        }
+                                    } else {
+                                        to_format.push(entry.path());
+                                    }
+                                }
+                                let clock = clock.clone();
+                                let formatted = blocking(move || {
+                                    let mut out = vec![];
+                                    for path in to_format {
+                                        format(path, &*clock, &mut out);
+                                    }
+                                    Ok(out)
+                                });
+                                out.extend(formatted.await.unwrap_or_default());
+                                if out.len() >= self.data_buffer_size() {
+                                    if let Err(error) = self.transfer_chunk(mem::take(&mut out)).await {
+                                        return self.abort_transfer(error).await;
+                                    }
+                                }
+                                if truncated.is_some() {
+                                    break 'listing;
+                                }
+                            }
+                            while let Some((dir, relative, depth)) = subdirs.pop_front() {
+                                if let Ok(entries) = blocking(move || read_dir(dir)).await {
+                                    listing = Some((entries, relative, depth));
+                                    break;
                                 }
-                                
                             }
                         }
                     } else {
+                        self.close_data_connection();
                         self = self
                             .send(Answer::new(
-                                ResultCode::InvalidParameterOrArgument,
+                                ResultCode::FileNotFound,
                                 "No such file or directory",
                             ))
                             .await?;
                         return Ok(self);
                     }
                 } else {
-                    if self.is_admin || path != self.server_root.join(CONFIG_FILE) {
-                        add_file_info(path, &mut out);
-                    }
+                    let formatted = blocking(move || {
+                        let mut out = vec![];
+                        format(path, &*clock, &mut out);
+                        Ok(out)
+                    });
+                    out.extend(formatted.await.unwrap_or_default());
+                }
+                if let Err(error) = self.transfer_chunk(out).await {
+                    return self.abort_transfer(error).await;
+                }
+                println!("{} -> and done", self.connection);
+                if let Some(max) = truncated {
+                    let message = format!("Listing truncated to {} entries", max);
+                    return self.finish_transfer_with(&message).await;
                 }
-                self = self.send_data(out).await?;
-                println!("-> and done");
             } else {
-                self = self
-                    .send(Answer::new(
-                        ResultCode::InvalidParameterOrArgument,
-                        "No such file or directory",
-                    ))
-                    .await?;
-            }
-            if self.data_writer.is_some() {
                 self.close_data_connection();
                 self = self
                     .send(Answer::new(
-                        ResultCode::ClosingDataConnection,
-                        "Transfer done",
+                        ResultCode::FileNotFound,
+                        "No such file or directory",
                     ))
                     .await?;
             }
+            self = self.finish_transfer().await?;
         } else {
             self = self
                 .send(Answer::new(
@@ -490,44 +1936,198 @@ impl Client {
         Ok(self)
     }
 
-    async fn send_data(mut self, data: Vec<u8>) -> Result<Self> {
-        if let Some(mut writer) = self.data_writer {
-            writer.send(data).await?;
-            self.data_writer = Some(writer);
+    /// Sends one chunk of a download or listing, after handling the control
+    /// commands received since the previous one. Fails if the client aborted
+    /// the transfer, which is then ended with `abort_transfer`.
+    async fn transfer_chunk(&mut self, data: Vec<u8>) -> io::Result<()> {
+        self.serve_control().await?;
+        if self.abort_requested {
+            return Err(aborted_by_client());
         }
-        Ok(self)
+        let len = data.len() as u64;
+        self.send_chunk(data).await?;
+        self.transferred += len;
+        self.downloaded += len;
+        Ok(())
     }
 
-    fn close_data_connection(&mut self) {
-        self.data_reader = None;
-        self.data_writer = None;
+    /// Closes the data connection of a completed transfer and tells the
+    /// client. Does nothing if the transfer already closed it.
+    async fn finish_transfer(self) -> Result<Self> {
+        self.finish_transfer_with("Transfer done").await
     }
 
-    async fn retr(mut self, path: PathBuf) -> Result<Self> {
-        if self.data_writer.is_some() {
-            let path = self.cwd.join(path);
-            let (new_self, res) = self.complete_path(path.clone());
-            self = new_self;
-            if let Ok(path) = res {
-                if path.is_file() && (self.is_admin || path != self.server_root.join(CONFIG_FILE)) {
-                    self = self
-                        .send(Answer::new(
-                            ResultCode::DataConnectionAlreadyOpen,
-                            "Starting to send file...",
-                        ))
-                        .await?;
-                    let mut file = File::open(path).await?;
-                    let mut out = vec![];
-                    file.read_to_end(&mut out).await?;
-                    self = self.send_data(out).await?;
-                    println!("-> file transfer done!");
-                } else {
-                    self = self
-                        .send(Answer::new(
-                            ResultCode::LocalErrorInProcessing,
-                            &format!(
-                                "\"{}\" doesn't exit",
-                                path.to_str()
+    async fn finish_transfer_with(mut self, message: &str) -> Result<Self> {
+        if self.data_writer.is_none() && self.data_reader.is_none() {
+            return Ok(self);
+        }
+        self.close_data_connection();
+        self.send(Answer::new(ResultCode::ClosingDataConnection, message)).await
+    }
+
+    /// Sends a chunk of a download. `send` only completes once the chunk is
+    /// flushed to the socket, so a slow client slows the transfer down rather
+    /// than having data pile up in memory. Meanwhile the control connection
+    /// is still served, a stalled client can be kept alive or ABOR it.
+    async fn send_chunk(&mut self, data: Vec<u8>) -> io::Result<()> {
+        let mut writer = match self.data_writer.take() {
+            Some(writer) => writer,
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, "no data connection")),
+        };
+        let send = writer.send(data);
+        tokio::pin!(send);
+        let res = loop {
+            tokio::select! {
+                res = &mut send => break res,
+                cmd = self.reader.next() => {
+                    let res = match cmd {
+                        Some(cmd) => self.transfer_command(cmd).await,
+                        None => Err(io::Error::new(io::ErrorKind::ConnectionAborted, "control connection closed")),
+                    };
+                    if let Err(error) = res {
+                        break Err(error);
+                    }
+                    if self.abort_requested {
+                        break Err(aborted_by_client());
+                    }
+                }
+            }
+        };
+        self.data_writer = Some(writer);
+        res
+    }
+
+    /// Ends a transfer whose data connection failed, the control connection
+    /// stays usable.
+    async fn abort_transfer(mut self, error: io::Error) -> Result<Self> {
+        println!("{} -> transfer aborted: {}", self.connection, error);
+        self.close_data_connection();
+        self = self
+            .send(Answer::new(
+                ResultCode::ConnectionClosed,
+                "Connection closed; transfer aborted",
+            ))
+            .await?;
+        // RFC 959: the reply to the aborted command comes first, then ABOR's own.
+        if mem::take(&mut self.abort_requested) {
+            self = self.send(Answer::new(ResultCode::ClosingDataConnection, "Abort command successful")).await?;
+        }
+        Ok(self)
+    }
+
+    /// Handles a command received while a transfer is running: NOOP
+    /// keepalives and STAT are answered right away, ABOR stops the transfer and
+    /// anything else is kept, in order, for afterwards. A client sending more
+    /// than `MAX_PENDING_COMMANDS` of those gets disconnected.
+    async fn transfer_command(&mut self, cmd: io::Result<Command>) -> io::Result<()> {
+        let answer = match cmd {
+            Ok(Command::Abor) => {
+                self.abort_requested = true;
+                return Ok(());
+            }
+            Ok(Command::NoOp) if self.pending.is_empty() => Answer::new(ResultCode::Ok, "Doing nothing"),
+            Ok(Command::Stat) if self.pending.is_empty() => Answer::new(ResultCode::SystemStatus, &self.status()),
+            _ if self.pending.len() == MAX_PENDING_COMMANDS => {
+                println!("{} Too many commands during a transfer", self.connection);
+                self.result = Some("too many commands");
+                let answer = Answer::new(ResultCode::ServiceNotAvailable, "Too many commands - closing control connection");
+                self.writer.send(answer).await?;
+                self.writer.close().await?;
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "too many commands"));
+            }
+            cmd => {
+                self.pending.push_back(cmd);
+                return Ok(());
+            }
+        };
+        self.writer.send(answer).await
+    }
+
+    /// Handles the commands already received, without waiting for more.
+    async fn serve_control(&mut self) -> io::Result<()> {
+        while let Some(Some(cmd)) = self.reader.next().now_or_never() {
+            self.transfer_command(cmd).await?;
+        }
+        Ok(())
+    }
+
+    /// One line telling who the session was and what it did, for the access
+    /// log. The byte counts are what went over data connections, listings
+    /// included.
+    fn summary(&self) -> String {
+        let user = if self.is_logged() { self.name.as_deref().unwrap_or("-") } else { "-" };
+        format!(
+            "Session summary: user={} commands={} uploaded={} downloaded={} duration={}s result={}",
+            user,
+            self.commands,
+            self.uploaded,
+            self.downloaded,
+            self.connected_at.elapsed().as_secs(),
+            self.result.unwrap_or("error"),
+        )
+    }
+
+    /// The STAT reply: the transfer progress and the negotiated parameters.
+    fn status(&self) -> String {
+        format!(
+            "Status: {} bytes transferred\n TYPE: {}\n MODE: {}\n STRU: {}\nEnd",
+            self.transferred,
+            self.transfer_type.name(),
+            self.transfer_mode.name(),
+            self.file_structure.name()
+        )
+    }
+
+    /// Bytes read from or written to the data connection at once.
+    fn data_buffer_size(&self) -> usize {
+        self.config.data_buffer_size.unwrap_or(DEFAULT_DATA_BUFFER_SIZE)
+    }
+
+    fn close_data_connection(&mut self) {
+        self.data_reader = None;
+        self.data_writer = None;
+    }
+
+    async fn retr(mut self, path: PathBuf) -> Result<Self> {
+        if self.data_writer.is_some() {
+            let path = self.cwd.join(path);
+            let (new_self, res) = self.complete_path(path.clone()).await;
+            self = new_self;
+            if let Ok(path) = res {
+                if is_special_file(&path).await {
+                    self.close_data_connection();
+                    return self.send(Answer::new(ResultCode::FileNotFound, "Not a regular file")).await;
+                }
+                let strict_ascii = self.transfer_type == TransferType::Ascii && self.config.strict_ascii == Some(true);
+                if strict_ascii && looks_binary(&path).await {
+                    self.close_data_connection();
+                    return self.send(Answer::new(ResultCode::FileNotFound, "File is binary; use TYPE I")).await;
+                }
+                if is_file(&path).await && (self.is_admin || path != self.server_root.join(CONFIG_FILE)) {
+                    self = self
+                        .send(Answer::new(
+                            ResultCode::DataConnectionAlreadyOpen,
+                            "Starting to send file...",
+                        ))
+                        .await?;
+                    let start = self.restart_offset.take().unwrap_or(0);
+                    // Bytes to send when RANG set an end.
+                    let len = self.restart_end.take().map(|end| end - start);
+                    let file = match File::open(path).await {
+                        Ok(mut file) => file.seek(SeekFrom::Start(start)).await.map(|_| file),
+                        Err(error) => Err(error),
+                    };
+                    return match file {
+                        Ok(file) => self.send_file(file, len).await,
+                        Err(error) => self.read_failed(error).await,
+                    };
+                } else {
+                    self = self
+                        .send(Answer::new(
+                            ResultCode::LocalErrorInProcessing,
+                            &format!(
+                                "\"{}\" doesn't exit",
+                                path.to_str()
                                     .ok_or_else(|| Error::Msg("No path".to_string()))?
                             ),
                         ))
@@ -553,16 +2153,44 @@ impl Client {
                 ))
                 .await?;
         }
-        if self.data_writer.is_some() {
-            self.close_data_connection();
-            self = self
-                .send(Answer::new(
-                    ResultCode::ClosingDataConnection,
-                    "Transfer done",
-                ))
-                .await?;
+        self.finish_transfer().await
+    }
+
+    /// Sends the content of `file`, up to `len` bytes if set, over the data
+    /// connection.
+    async fn send_file<R: tokio::io::AsyncRead + Unpin>(mut self, mut file: R, mut len: Option<u64>) -> Result<Self> {
+        let mut buf = vec![0; self.data_buffer_size()];
+        self.transferred = 0;
+        let throttle = Throttle::new(self.max_rate());
+        loop {
+            let max = len.map_or(buf.len(), |len| buf.len().min(len as usize));
+            let read = match file.read(&mut buf[..max]).await {
+                Ok(read) => read,
+                Err(error) => return self.read_failed(error).await,
+            };
+            if let Some(ref mut len) = len {
+                *len -= read as u64;
+            }
+            if read == 0 {
+                break;
+            }
+            if let Err(error) = self.transfer_chunk(buf[..read].to_vec()).await {
+                // Typically the client closing the data connection
+                // once it got what it wanted.
+                return self.abort_transfer(error).await;
+            }
+            throttle.wait(self.transferred).await;
         }
-        Ok(self)
+        println!("{} -> file transfer done!", self.connection);
+        self.finish_transfer().await
+    }
+
+    /// Gives up on a download the file of which can't be read anymore (a
+    /// failing disk, a file truncated meanwhile...), keeping the session.
+    async fn read_failed(mut self, error: io::Error) -> Result<Self> {
+        println!("{} -> couldn't read the file: {}", self.connection, error);
+        self.close_data_connection();
+        self.send(Answer::new(ResultCode::LocalErrorInProcessing, "Error reading the file; transfer aborted")).await
     }
 
     async fn stor(mut self, path: PathBuf) -> Result<Self> {
@@ -573,18 +2201,135 @@ impl Client {
             }
 
             let path = self.cwd.join(path);
+            let (new_self, res) = self.resolve_for_create(path).await;
+            self = new_self;
+            let file_path = res.ok();
+            // The config file, reached through a link, and anything outside
+            // the upload directory are off limits.
+            let upload_dir = match self.user.as_ref().and_then(|user| user.upload_dir.clone()) {
+                Some(dir) => {
+                    let server_root = self.server_root.clone();
+                    Some(blocking(move || resolve_path(&server_root, dir)).await)
+                }
+                None => None,
+            };
+            if let Some(path) = file_path.as_ref() {
+                let is_config = !self.is_admin && *path == self.server_root.join(CONFIG_FILE);
+                let outside_upload_dir = upload_dir
+                    .as_ref()
+                    .is_some_and(|dir| !dir.as_ref().is_ok_and(|dir| path.starts_with(dir)));
+                if is_config || outside_upload_dir {
+                    self.close_data_connection();
+                    return self.send(Answer::new(ResultCode::FileNotFound, "Permission denied")).await;
+                }
+            }
+            let file_path = match file_path {
+                Some(file_path) => file_path,
+                None => {
+                    self.close_data_connection();
+                    self = self
+                        .send(Answer::new(
+                            ResultCode::FileNotFound,
+                            "No such file or directory",
+                        ))
+                        .await?;
+                    return Ok(self);
+                }
+            };
+            if is_special_file(&file_path).await {
+                self.close_data_connection();
+                return self.send(Answer::new(ResultCode::FileNotFound, "Not a regular file")).await;
+            }
+
+            let restart_offset = self.restart_offset.take();
+            let limit = match self.quota_limit(&file_path, restart_offset).await {
+                Ok(limit) => limit,
+                Err(error) => {
+                    self.close_data_connection();
+                    println!("{} -> couldn't compute the quota usage: {}", self.connection, error);
+                    return self.send(Answer::new(ResultCode::LocalErrorInProcessing, "Couldn't compute the usage")).await;
+                }
+            };
+            let is_config = file_path == self.server_root.join(CONFIG_FILE);
+            // Resumed uploads only carry part of the file, the config can't be
+            // checked before it is overwritten.
+            if is_config && restart_offset.is_some() {
+                self.close_data_connection();
+                return self.send(Answer::new(ResultCode::FileNotFound, "Invalid config")).await;
+            }
+            // A new upload goes to a temporary file, renamed once complete, so
+            // a failed one never leaves a truncated file behind. A resumed one
+            // is written in place: what was received can be resumed again.
+            let upload = match restart_offset {
+                Some(_) => file_path.clone(),
+                None => upload_path(&file_path, self.connection.id),
+            };
+            let mut file = match open_upload(&upload, restart_offset).await {
+                Ok(file) => file,
+                Err(error) => {
+                    println!("{} -> couldn't create {}: {}", self.connection, upload.display(), error);
+                    self.close_data_connection();
+                    return self.send(write_error_answer(&error)).await;
+                }
+            };
             self = self
                 .send(Answer::new(
                     ResultCode::DataConnectionAlreadyOpen,
                     "Starting to send file...",
                 ))
                 .await?;
-            let (data, new_self) = self.receive_data().await?;
+            let expected_size = self.expected_size.take();
+            // RANG only limits downloads, an upload is written from its start on.
+            self.restart_end = None;
+            let (new_self, res) = self.receive_data(&mut file, limit).await;
             self = new_self;
-            let mut file = File::create(path).await?;
-            file.write_all(&data).await?;
-            println!("-> file transfer done!");
             self.close_data_connection();
+            drop(file);
+            let res = match res {
+                Ok(()) if expected_size.is_some_and(|size| self.transferred < size) => Err(UploadError::Transfer(
+                    io::Error::new(io::ErrorKind::UnexpectedEof, "fewer bytes than announced by ALLO"),
+                )),
+                res => res,
+            };
+            if res.is_err() && restart_offset.is_none() {
+                println!("{} -> upload failed, discarding {}", self.connection, file_path.display());
+                let _ = tokio::fs::remove_file(&upload).await;
+            }
+            match res {
+                Ok(()) => {}
+                Err(_) if limit.is_some_and(|limit| self.transferred > limit) => {
+                    return self.send(Answer::new(ResultCode::ExceededStorageAllocation, "Quota exceeded")).await;
+                }
+                Err(UploadError::Transfer(error)) => return self.abort_transfer(error).await,
+                Err(UploadError::Write(error)) => {
+                    println!("{} -> couldn't write {}: {}", self.connection, file_path.display(), error);
+                    return self.send(write_error_answer(&error)).await;
+                }
+            }
+            if is_config {
+                // A broken config would lock everybody out at the next reload.
+                let path = upload.clone();
+                let valid = blocking(move || std::fs::read(path))
+                    .await
+                    .map_err(Error::from)
+                    .and_then(|data| str::from_utf8(&data).map(str::to_owned).map_err(Error::from))
+                    .and_then(|content| Config::parse(&content))
+                    .is_ok_and(|config| config.validate().is_ok());
+                if !valid {
+                    println!("{} -> rejected invalid config upload", self.connection);
+                    let _ = tokio::fs::remove_file(&upload).await;
+                    return self.send(Answer::new(ResultCode::FileNotFound, "Invalid config")).await;
+                }
+            }
+            if restart_offset.is_none() {
+                if let Err(error) = tokio::fs::rename(&upload, &file_path).await {
+                    println!("{} -> couldn't write {}: {}", self.connection, file_path.display(), error);
+                    let _ = tokio::fs::remove_file(&upload).await;
+                    return self.send(write_error_answer(&error)).await;
+                }
+                apply_umask(&file_path, 0o666, self.umask);
+            }
+            println!("{} -> file transfer done!", self.connection);
             self = self
                 .send(Answer::new(
                     ResultCode::ClosingDataConnection,
@@ -602,27 +2347,106 @@ impl Client {
         Ok(self)
     }
 
-    async fn receive_data(mut self) -> Result<(Vec<u8>, Self)> {
-        let mut file_data = vec![];
-        if self.data_reader.is_none() {
-            return Ok((vec![], self));
-        }
-
-        let mut reader = self
-            .data_reader
-            .take()
-            .ok_or_else(|| Error::Msg("No data reader".to_string()))?;
+    /// Writes what the data connection brings to `file` until the client
+    /// closes it, failing if the connection breaks before that: what was
+    /// received so far can't be trusted to be the whole file. Also fails as
+    /// soon as more than `limit` bytes arrive.
+    async fn receive_data(mut self, file: &mut File, limit: Option<u64>) -> (Self, result::Result<(), UploadError>) {
+        let mut reader = match self.data_reader.take() {
+            Some(reader) => reader,
+            None => {
+                let error = io::Error::new(io::ErrorKind::NotConnected, "no data connection");
+                return (self, Err(UploadError::Transfer(error)));
+            }
+        };
+        self.transferred = 0;
+        let throttle = Throttle::new(self.max_rate());
 
-        while let Some(data) = reader.next().await {
+        loop {
+            let data = tokio::select! {
+                data = reader.next() => data,
+                cmd = self.reader.next() => {
+                    let res = match cmd {
+                        Some(cmd) => self.transfer_command(cmd).await,
+                        None => Err(io::Error::new(io::ErrorKind::ConnectionAborted, "control connection closed")),
+                    };
+                    if let Err(error) = res {
+                        return (self, Err(UploadError::Transfer(error)));
+                    }
+                    if self.abort_requested {
+                        return (self, Err(UploadError::Transfer(aborted_by_client())));
+                    }
+                    continue;
+                }
+            };
             match data {
-                Ok(data) => file_data.extend(&data),
-                Err(e) => {
-                    eprintln!("get cmd error: {}", e);
+                Some(Ok(data)) => {
+                    self.transferred += data.len() as u64;
+                    self.uploaded += data.len() as u64;
+                    if limit.is_some_and(|limit| self.transferred > limit) {
+                        return (self, Err(UploadError::Transfer(io::Error::other("quota exceeded"))));
+                    }
+                    if let Err(error) = file.write_all(&data).await {
+                        return (self, Err(UploadError::Write(error)));
+                    }
+                    throttle.wait(self.transferred).await;
                 }
+                Some(Err(error)) => return (self, Err(UploadError::Transfer(error))),
+                None => break,
             }
         }
-    
-        Ok((file_data, self))
+
+        // Writes may only fail once flushed.
+        let res = file.flush().await.map_err(UploadError::Write);
+        (self, res)
+    }
+
+    /// How many bytes an upload to `path` starting at `offset` may bring for
+    /// the logged in user to stay within their quota, `None` when there's no
+    /// limit. The quota counts what is under their home, users without one
+    /// aren't limited.
+    async fn quota_limit(&self, path: &Path, offset: Option<u64>) -> io::Result<Option<u64>> {
+        let (quota, home) = match self.user {
+            Some(ref user) => match (user.quota, user.home.clone()) {
+                (Some(quota), Some(home)) => (quota, home),
+                _ => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+        let server_root = self.server_root.clone();
+        let path = path.to_path_buf();
+        // The walk can take a while, keep it off the runtime.
+        blocking(move || {
+            let (home, usage) = match home_usage(&server_root, home)? {
+                Some(found) => found,
+                None => return Ok(None),
+            };
+            let existing = match path.metadata() {
+                Ok(metadata) if path.starts_with(&home) => metadata.len(),
+                _ => 0,
+            };
+            // What the file may grow to, the rest of the home staying as is.
+            let room = quota.saturating_sub(usage - existing.min(usage));
+            Ok(Some(match offset {
+                // A resumed upload keeps the existing bytes.
+                Some(_) if existing > room => 0,
+                Some(offset) => room.saturating_sub(offset),
+                None => room,
+            }))
+        })
+        .await
+    }
+
+    /// Seconds the session may stay silent before being closed.
+    fn idle_timeout(&self) -> u64 {
+        self.idle_timeout
+            .or(self.config.idle_timeout)
+            .unwrap_or(DEFAULT_IDLE_TIMEOUT)
+    }
+
+    /// Transfer rate limit of the logged in user, resolved at login.
+    fn max_rate(&self) -> Option<u64> {
+        self.user.as_ref().and_then(|user| user.max_rate)
     }
 
     fn is_logged(&self) -> bool {
@@ -630,58 +2454,465 @@ impl Client {
     }
 }
 
+impl Drop for Client {
+    /// Closes the data connection left open when the session ends, whichever
+    /// way it ends, and logs the summary of the session.
+    fn drop(&mut self) {
+        if self.data_reader.is_some() || self.data_writer.is_some() {
+            println!("{} Closing the unused data connection", self.connection);
+            self.close_data_connection();
+        }
+        println!("{} {}", self.connection, self.summary());
+    }
+}
+
 #[tokio::main]
 async fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let config = Config::new(CONFIG_FILE).expect("Error while lodding config...");
+    let config = Config::new(CONFIG_FILE).map_err(|error| format!("Error while loading config: {}", error))?;
+    config.validate().map_err(|error| format!("Invalid config: {}", error))?;
     let server_root = env::current_dir()?;
     server(server_root, config).await?;
     Ok(())
 }
 
 async fn server(server_root: PathBuf, config: Config) -> io::Result<()> {
-    let port = config.server_port.unwrap_or(DEFAULT_PORT);
-    let addr = SocketAddr::new(IpAddr::V4(config.server_addr.as_ref().unwrap_or(&"127.0.0.1".to_owned()).parse().expect("Invalid Ipv4 address...")), port);
-    // let addr = "127.0.0.1:1234";
-    let mut listener = TcpListener::bind(addr).await?;
+    let mut listeners = vec![];
+    for addr in config.listen_addrs() {
+        println!("Listening on {}", addr);
+        listeners.push((TcpListener::bind(addr).await?, None));
+    }
+    let acceptor = match (config.tls_cert.as_ref(), config.tls_key.as_ref()) {
+        (Some(cert), Some(key)) => Some(tls::acceptor(Path::new(cert), Path::new(key), config.tls_min_version.as_deref())?),
+        _ => None,
+    };
+    // Validation makes sure there's a certificate for these.
+    if let Some(ref acceptor) = acceptor {
+        for addr in config.implicit_tls_addrs() {
+            println!("Listening on {} (implicit TLS)", addr);
+            listeners.push((TcpListener::bind(addr).await?, Some(acceptor.clone())));
+        }
+    }
+    // The accounts have to be looked up before the chroot hides /etc/passwd.
+    let run_as = match config.run_as_user {
+        Some(ref user) => Some(lookup_ids(user, config.run_as_group.as_deref())?),
+        None => None,
+    };
+    let server_root = if config.chroot == Some(true) {
+        enter_chroot(&server_root)?
+    } else {
+        server_root
+    };
+    if let Some(ids) = run_as {
+        drop_privileges(ids)?;
+    }
+    let shared = Arc::new(Shared {
+        tls: acceptor,
+        ..Shared::new(config)
+    });
+    tokio::select! {
+        res = serve_shared(listeners, server_root, shared.clone()) => res,
+        _ = shutdown_signal() => {
+            println!("Shutting down");
+            shutdown(&shared).await;
+            Ok(())
+        }
+    }
+}
+
+/// Resolves once the process is asked to stop, by SIGTERM or Ctrl-C.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Closes every connection with a 421, idle ones right away and the ones in
+/// the middle of a command once it is done, giving them up to
+/// `shutdown_grace_period` seconds. Returns how many were still open then.
+async fn shutdown(shared: &Shared) -> usize {
+    let grace_period = {
+        let config = shared.config.read().unwrap();
+        Duration::from_secs(config.shutdown_grace_period.unwrap_or(DEFAULT_SHUTDOWN_GRACE_PERIOD))
+    };
+    let kicks: Vec<_> = shared
+        .sessions
+        .lock()
+        .unwrap()
+        .values_mut()
+        .filter_map(|session| session.kick.take())
+        .collect();
+    for kick in kicks {
+        let _ = kick.send("Service shutting down");
+    }
+    let deadline = Instant::now() + grace_period;
+    while !shared.sessions.lock().unwrap().is_empty() && Instant::now() < deadline {
+        delay_for(Duration::from_millis(50)).await;
+    }
+    let remaining = shared.sessions.lock().unwrap().len();
+    if remaining > 0 {
+        println!("Grace period expired, closing {} active connections", remaining);
+    }
+    remaining
+}
+
+/// Makes `server_root` the root directory of the process, returning what the
+/// server root is from then on. Only root may do that.
+#[cfg(unix)]
+fn enter_chroot(server_root: &Path) -> io::Result<PathBuf> {
+    if !nix::unistd::geteuid().is_root() {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "chroot needs the server to run as root"));
+    }
+    nix::unistd::chroot(server_root)
+        .and_then(|()| nix::unistd::chdir("/"))
+        .map_err(|error| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("couldn't chroot into {} (it needs root): {}", server_root.display(), error),
+            )
+        })?;
+    println!("Chrooted into {}", server_root.display());
+    Ok(PathBuf::from("/"))
+}
+
+#[cfg(not(unix))]
+fn enter_chroot(_server_root: &Path) -> io::Result<PathBuf> {
+    Err(io::Error::new(io::ErrorKind::Other, "chroot is only supported on Unix"))
+}
+
+/// The uid and gid of `user` and `group`, names or numeric IDs. Without a
+/// group, the primary group of the user.
+#[cfg(unix)]
+fn lookup_ids(user: &str, group: Option<&str>) -> io::Result<(nix::unistd::Uid, nix::unistd::Gid)> {
+    use nix::unistd::{Gid, Group, Uid, User};
+
+    let not_found = |what: &str, name: &str| io::Error::new(io::ErrorKind::NotFound, format!("no {} \"{}\"", what, name));
+    let account = match user.parse() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+        Err(_) => User::from_name(user),
+    }
+    .map_err(io::Error::from)?;
+    let uid = match (account.as_ref(), user.parse()) {
+        (Some(account), _) => account.uid,
+        // A bare uid doesn't need an account.
+        (None, Ok(uid)) => Uid::from_raw(uid),
+        (None, Err(_)) => return Err(not_found("user", user)),
+    };
+    let gid = match group {
+        Some(group) => match group.parse() {
+            Ok(gid) => Gid::from_raw(gid),
+            Err(_) => Group::from_name(group)
+                .map_err(io::Error::from)?
+                .ok_or_else(|| not_found("group", group))?
+                .gid,
+        },
+        None => account.ok_or_else(|| not_found("user", user))?.gid,
+    };
+    Ok((uid, gid))
+}
 
+#[cfg(not(unix))]
+fn lookup_ids(_user: &str, _group: Option<&str>) -> io::Result<((), ())> {
+    Err(io::Error::new(io::ErrorKind::Other, "run_as_user is only supported on Unix"))
+}
+
+/// Switches the process to `ids` for good: supplementary groups, group and
+/// then user, checking root can't be taken back.
+#[cfg(unix)]
+fn drop_privileges((uid, gid): (nix::unistd::Uid, nix::unistd::Gid)) -> io::Result<()> {
+    use nix::unistd::{setgid, setgroups, setuid, Uid};
+
+    setgroups(&[gid])
+        .and_then(|()| setgid(gid))
+        .and_then(|()| setuid(uid))
+        .map_err(|error| io::Error::new(io::ErrorKind::PermissionDenied, format!("couldn't switch to uid {}: {}", uid, error)))?;
+    if !uid.is_root() && setuid(Uid::from_raw(0)).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "root privileges could be regained"));
+    }
+    println!("Running as uid {}, gid {}", uid, gid);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn drop_privileges(_ids: ((), ())) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "run_as_user is only supported on Unix"))
+}
+
+/// Accepts connections on every listener, the ones paired with an acceptor
+/// being implicit TLS ones.
+async fn serve_shared(
+    listeners: Vec<(TcpListener, Option<TlsAcceptor>)>,
+    server_root: PathBuf,
+    shared: Arc<Shared>,
+) -> io::Result<()> {
+    let accept_loops = listeners
+        .into_iter()
+        .map(|(listener, tls)| accept_loop(listener, tls, server_root.clone(), shared.clone()));
+    future::try_join_all(accept_loops).await?;
+    Ok(())
+}
+
+async fn accept_loop(
+    mut listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    server_root: PathBuf,
+    shared: Arc<Shared>,
+) -> io::Result<()> {
     loop {
         let (socket, addr) = listener.accept().await?;
+        tokio::spawn(start_session(socket, addr, tls.clone(), server_root.clone(), shared.clone()));
+    }
+}
+
+/// Sets up an accepted connection and serves it.
+async fn start_session(
+    mut socket: TcpStream,
+    mut addr: SocketAddr,
+    tls: Option<TlsAcceptor>,
+    server_root: PathBuf,
+    shared: Arc<Shared>,
+) -> result::Result<(), ()> {
+    let (proxied, login_timeout) = {
+        let config = shared.config.read().unwrap();
+        let login_timeout = config.login_timeout.unwrap_or(DEFAULT_LOGIN_TIMEOUT);
+        (config.accept_proxy_protocol == Some(true), Duration::from_secs(login_timeout))
+    };
+    if proxied {
+        // The connection comes from the load balancer, the client's real
+        // address is in the header it sends first.
+        match timeout(login_timeout, proxy::read_header(&mut socket)).await {
+            Ok(Ok(source)) => addr = source.unwrap_or(addr),
+            Ok(Err(error)) => {
+                println!("Bad PROXY header from {}, dropping it: {}", addr, error);
+                return Err(());
+            }
+            Err(_) => {
+                println!("No PROXY header from {}, dropping it", addr);
+                return Err(());
+            }
+        }
+    }
+
+    if !shared.allow_connection(addr.ip()) {
+        println!("Too many connections from {}, dropping it", addr.ip());
+        let _ = socket.write_all(b"421 Too many connections from your address\r\n").await;
+        return Err(());
+    }
+    let connection = Connection {
+        id: shared.next_connection_id.fetch_add(1, Ordering::Relaxed),
+        addr,
+    };
+    println!("{} New client", connection);
+    let options = SocketOptions::new(&shared.config.read().unwrap());
+    if let Err(error) = options.apply(&socket) {
+        println!("{} Couldn't set socket options: {}", connection, error);
+    }
+    match tls {
+        // The handshake counts against the login timeout, a client stalling
+        // in it would hold the connection forever otherwise.
+        Some(acceptor) => match timeout(login_timeout, acceptor.accept(socket)).await {
+            Ok(Ok(stream)) => handle_client(stream, true, connection, server_root, shared).await,
+            Ok(Err(error)) => {
+                println!("{} TLS handshake failed: {}", connection, error);
+                Err(())
+            }
+            Err(_) => {
+                println!("{} TLS handshake timed out", connection);
+                Err(())
+            }
+        },
+        None => handle_client(socket, false, connection, server_root, shared).await,
+    }
+}
+
+/// Options of the control and data sockets. Commands are small and
+/// interactive, so they're sent without delay by default. Keepalive probes
+/// detect peers gone silently and keep NAT mappings of idle sessions.
+struct SocketOptions {
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    send_buffer: Option<usize>,
+    recv_buffer: Option<usize>,
+}
+
+impl SocketOptions {
+    fn new(config: &Config) -> SocketOptions {
+        let keepalive = config.tcp_keepalive.unwrap_or(DEFAULT_TCP_KEEPALIVE);
+        SocketOptions {
+            nodelay: config.tcp_nodelay.unwrap_or(true),
+            keepalive: if keepalive == 0 { None } else { Some(Duration::from_secs(keepalive)) },
+            send_buffer: config.socket_send_buffer,
+            recv_buffer: config.socket_recv_buffer,
+        }
+    }
+
+    /// The options of data sockets, whose buffers follow `data_buffer_size`.
+    fn data(config: &Config) -> SocketOptions {
+        let options = SocketOptions::new(config);
+        SocketOptions {
+            send_buffer: config.data_buffer_size.or(options.send_buffer),
+            recv_buffer: config.data_buffer_size.or(options.recv_buffer),
+            ..options
+        }
+    }
 
-        let address = format!("[address: {}]", addr);
-        println!("New client: {}", address);
-        let server_root_copy = server_root.clone();
-        let config_copy = config.clone();
-        tokio::spawn(async move { handle_client(socket, server_root_copy, config_copy).await });
+    fn apply(&self, socket: &TcpStream) -> io::Result<()> {
+        socket.set_nodelay(self.nodelay)?;
+        socket.set_keepalive(self.keepalive)?;
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
     }
 }
 
-async fn handle_client(
-    stream: TcpStream,
+async fn handle_client<S: ControlStream + 'static>(
+    stream: S,
+    tls: bool,
+    connection: Connection,
     server_root: PathBuf,
-    config: Config,
+    shared: Arc<Shared>,
 ) -> result::Result<(), ()> {
-    client(stream, server_root, config)
-        .await
-        .map_err(|error| println!("Error handling client: {}", error))
+    client(stream, tls, connection, server_root, shared).await.or_else(|error| {
+        if is_disconnect(&error) {
+            println!("{} Client disconnected: {}", connection, error);
+            Ok(())
+        } else {
+            println!("{} Error handling client: {}", connection, error);
+            Err(())
+        }
+    })
+}
+
+/// Whether `error` only means the peer went away, which is a normal end of
+/// session rather than something worth reporting.
+fn is_disconnect(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+    )
 }
 
-async fn client(stream: TcpStream, server_root: PathBuf, config: Config) -> io::Result<()> {
+async fn client<S: ControlStream + 'static>(
+    stream: S,
+    tls: bool,
+    connection: Connection,
+    server_root: PathBuf,
+    shared: Arc<Shared>,
+) -> io::Result<()> {
+    let stream: Box<dyn ControlStream> = Box::new(stream);
     let framed = Framed::new(stream, FtpCodec);
-    let (mut writer, mut reader) = framed.split();
+    let (mut writer, reader) = framed.split();
     // let (writer, reader) = stream.framed(FtpCodec).split();
-    writer
-        .send(Answer::new(
-            ResultCode::ServiceReadyForNewUser,
-            "Welcome to this FTP server!",
-        ))
-        .await?;
-    let mut client = Client::new(writer, server_root, config);
+    let (greeting_delay, advertise_utf8) = {
+        let config = shared.config.read().unwrap();
+        (config.greeting_delay_ms.unwrap_or(0), config.advertise_utf8 == Some(true))
+    };
+    if greeting_delay > 0 {
+        delay_for(Duration::from_millis(greeting_delay)).await;
+    }
+    let greeting = if advertise_utf8 {
+        "Welcome to this FTP server! (UTF8)"
+    } else {
+        "Welcome to this FTP server!"
+    };
+    writer.send(Answer::new(ResultCode::ServiceReadyForNewUser, greeting)).await?;
+    let (_session, mut kicked, mut messages) = SessionGuard::new(shared.clone(), connection);
+    let mut client = Client::new(writer, reader, connection, server_root, shared);
+    client.tls = tls;
+    let login_timeout = client.config.login_timeout.unwrap_or(DEFAULT_LOGIN_TIMEOUT);
+    let login_deadline = Instant::now() + Duration::from_secs(login_timeout);
 
-    while let Some(cmd) = reader.next().await {
+    loop {
+        // Until the client is logged in, it only has `login_timeout` seconds
+        // to send commands so unauthenticated connections can't pile up.
+        // Afterwards it gets closed once silent for `idle_timeout` seconds. The
+        // timer only runs while waiting for the next command, so the time spent
+        // in a command (e.g. PASV waiting for the data connection, which has
+        // its own DATA_CONNECTION_TIMEOUT) doesn't count as idle.
+        let deadline = if client.is_logged() {
+            Instant::now() + Duration::from_secs(client.idle_timeout())
+        } else {
+            login_deadline
+        };
+        let cmd = if let Some(cmd) = client.pending.pop_front() {
+            // Sent during the last transfer.
+            Ok(Some(cmd))
+        } else {
+            tokio::select! {
+                cmd = timeout_at(deadline, client.reader.next()) => cmd,
+                // Not while a command runs: a transfer gets to finish first.
+                Ok(reason) = &mut kicked => {
+                    println!("{} {}", client.connection, reason);
+                    client.result = Some("kicked");
+                    client.close_control(reason).await?;
+                    break;
+                }
+                // Only between commands too, so a notice never gets in the
+                // way of the reply to one.
+                Some(message) = messages.recv() => {
+                    client = client.send(Answer::new(ResultCode::ServiceReadyForNewUser, &message)).await?;
+                    continue;
+                }
+            }
+        };
+        let cmd = match cmd {
+            Ok(cmd) => cmd,
+            Err(_) if client.is_logged() => {
+                println!("{} Idle timeout", client.connection);
+                client.result = Some("idle timeout");
+                client.close_control("Timeout - closing control connection").await?;
+                break;
+            }
+            Err(_) => {
+                println!("{} Login timeout", client.connection);
+                client.result = Some("login timeout");
+                client.close_control("Login timeout - closing control connection").await?;
+                break;
+            }
+        };
+        let cmd = match cmd {
+            Some(cmd) => cmd,
+            None => {
+                println!("{} Client closed the connection", client.connection);
+                client.result = Some("closed by client");
+                break;
+            }
+        };
         client = match cmd {
-            Ok(cmd) => client.handle_cmd(cmd).await?,
+            Ok(cmd) => {
+                let quit = cmd == Command::Quit;
+                client.update_session(Some(cmd.as_ref()));
+                let mut client = client.handle_cmd(cmd).await?;
+                client.update_session(None);
+                if quit {
+                    client.result = Some("quit");
+                    break;
+                }
+                client
+            }
+            Err(ref e) if is_disconnect(e) => {
+                println!("{} Client disconnected: {}", client.connection, e);
+                client.result = Some("disconnected");
+                break;
+            }
             Err(e) => {
-                eprintln!("get cmd error: {}", e);
+                eprintln!("{} get cmd error: {}", client.connection, e);
                 client
             }
         }
@@ -694,19 +2925,42 @@ const MONTHS: [&'static str; 12] = [
     "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
 ];
 
-fn add_file_info(path: PathBuf, out: &mut Vec<u8>) {
-    let extra = if path.is_dir() { "/" } else { "" };
-    let is_dir = if path.is_dir() { "d" } else { "-" };
+fn add_file_name(path: PathBuf, _: &dyn Clock, out: &mut Vec<u8>) {
+    if let Some(name) = path.file_name() {
+        out.extend(format!("{}\r\n", name.to_string_lossy()).as_bytes());
+    }
+}
+
+// Half of an average Gregorian year, as `ls` counts it.
+const SIX_MONTHS: i64 = 31_556_952 / 2;
+
+/// The date column of LIST, like `ls -l`: the time of day for the files
+/// modified in the last six months, the year for older ones and the ones
+/// from the future.
+fn list_date(mtime: time::Tm, now: time::Timespec) -> String {
+    let age = now.sec - mtime.to_timespec().sec;
+    let month = MONTHS[mtime.tm_mon as usize];
+    if (0..SIX_MONTHS).contains(&age) {
+        format!("{} {:>2} {:02}:{:02}", month, mtime.tm_mday, mtime.tm_hour, mtime.tm_min)
+    } else {
+        format!("{} {:>2}  {}", month, mtime.tm_mday, mtime.tm_year + 1900)
+    }
+}
+
+fn add_file_info(path: PathBuf, clock: &dyn Clock, out: &mut Vec<u8>) {
+    // A single stat: the entry may have been deleted since `read_dir` listed
+    // it, it is then left out rather than shown half known.
     let meta = match ::std::fs::metadata(&path) {
         Ok(meta) => meta,
         _ => return,
     };
+    let extra = if meta.is_dir() { "/" } else { "" };
+    let is_dir = if meta.is_dir() { "d" } else { "-" };
     let (time, file_size) = get_file_info(&meta);
-    let path = match path.to_str() {
-        Some(path) => match path.split("/").last() {
-            Some(path) => path,
-            _ => return,
-        },
+    // Names that aren't valid UTF-8 are shown with replacement characters
+    // rather than being left out of the listing.
+    let path = match path.file_name() {
+        Some(path) => path.to_string_lossy(),
         _ => return,
     };
     let rights = if meta.permissions().readonly() {
@@ -716,20 +2970,2567 @@ fn add_file_info(path: PathBuf, out: &mut Vec<u8>) {
     };
 
     let file_str = format!(
-        "{is_dir}{rights} {links} {owner} {group} {size} {month} {day} {hour}:{min} {path}{extra}\r\n",
+        "{is_dir}{rights} {links} {owner} {group} {size} {date} {path}{extra}\r\n",
         is_dir = is_dir,
         rights = rights,
         links = 1,           // number of links
         owner = "anonymous", // owner name
         group = "anonymous", // group name
         size = file_size,
-        month = MONTHS[time.tm_mon as usize],
-        day = time.tm_mday,
-        hour = time.tm_hour,
-        min = time.tm_min,
+        date = list_date(time, clock.now()),
         path = path,
         extra = extra
     );
     out.extend(file_str.as_bytes());
-    println!("==> {:?}", &file_str);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use std::io;
+    use std::path::{Component, PathBuf};
+
+    use proptest::prelude::*;
+
+    use super::{invalid_path, resolve_path, resolve_path_allow_missing, MAX_PENDING_COMMANDS};
+    use crate::config::{Config, User};
+    use crate::testing::{serve, start_server, test_config, TestClient};
+
+    #[tokio::test]
+    async fn test_stor_discards_incomplete_upload() {
+        let root = tempfile::tempdir().unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        assert!(client.cmd("ALLO 100").await.starts_with("200"));
+        let mut data = client.pasv().await;
+        assert!(client.cmd("STOR partial.bin").await.starts_with("125"));
+        data.write_all(&[0; 10]).await.unwrap();
+        drop(data);
+
+        assert!(client.reply().await.starts_with("426"));
+        // Neither the file nor the temporary one it was received into.
+        assert_eq!(std::fs::read_dir(root.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stor_complete_upload() {
+        let root = tempfile::tempdir().unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        assert!(client.cmd("ALLO 10").await.starts_with("200"));
+        let mut data = client.pasv().await;
+        assert!(client.cmd("STOR complete.bin").await.starts_with("125"));
+        data.write_all(&[0; 10]).await.unwrap();
+        drop(data);
+
+        assert!(client.reply().await.starts_with("226"));
+        assert_eq!(std::fs::read(root.path().join("complete.bin")).unwrap(), vec![0; 10]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_stor_disk_full() {
+        use futures::StreamExt;
+        use std::sync::Arc;
+        use tokio_util::codec::Framed;
+
+        use super::{write_error_answer, Client, Connection, ControlStream, Shared, UploadError};
+        use crate::codec::FtpCodec;
+        use crate::ftp::ResultCode;
+
+        // STOR can't reach /dev/full from inside the server root, receive
+        // into it directly.
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _control = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, addr) = listener.accept().await.unwrap();
+        let mut data = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (data_socket, _) = listener.accept().await.unwrap();
+
+        let socket: Box<dyn ControlStream> = Box::new(socket);
+        let (writer, reader) = Framed::new(socket, FtpCodec).split();
+        let connection = Connection { id: 1, addr };
+        let mut client = Client::new(writer, reader, connection, std::env::temp_dir(), Arc::new(Shared::new(test_config())));
+        client.open_data_connection(data_socket);
+
+        data.write_all(&[0; 10]).await.unwrap();
+        drop(data);
+        let mut file = tokio::fs::OpenOptions::new().write(true).open("/dev/full").await.unwrap();
+        let (_client, res) = client.receive_data(&mut file, None).await;
+        match res {
+            Err(UploadError::Write(error)) => {
+                assert_eq!(write_error_answer(&error).code, ResultCode::ExceededStorageAllocation)
+            }
+            _ => panic!("the write didn't fail"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_stor_through_escaping_symlink() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path().join("file"), root.path().join("dangling")).unwrap();
+        std::fs::write(outside.path().join("existing"), b"").unwrap();
+        std::os::unix::fs::symlink(outside.path().join("existing"), root.path().join("link")).unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        let _data = client.pasv().await;
+        assert!(client.cmd("STOR dangling").await.starts_with("550"));
+        let _data = client.pasv().await;
+        assert!(client.cmd("STOR link").await.starts_with("550"));
+        assert!(!outside.path().join("file").exists());
+        assert!(client.cmd("MKD dangling").await.starts_with("550"));
+        assert!(client.cmd("MKD new").await.starts_with("257"));
+        assert!(root.path().join("new").is_dir());
+
+        std::fs::write(root.path().join("config.toml"), b"").unwrap();
+        std::os::unix::fs::symlink(root.path().join("config.toml"), root.path().join("settings")).unwrap();
+        let _data = client.pasv().await;
+        assert_eq!(client.cmd("STOR settings").await, "550 Permission denied\r\n");
+        assert!(client.cmd("NOOP").await.starts_with("200"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_list_non_utf8_name() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join(OsStr::from_bytes(b"bad\xffname")), b"").unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        let mut data = client.pasv().await;
+        assert!(client.cmd("LIST").await.starts_with("125"));
+        let mut listing = String::new();
+        data.read_to_string(&mut listing).await.unwrap();
+        assert!(client.reply().await.starts_with("226"));
+        assert!(listing.contains("bad\u{FFFD}name\r\n"), "{}", listing);
+    }
+
+    #[tokio::test]
+    async fn test_default_transfer_type_binary() {
+        let root = tempfile::tempdir().unwrap();
+        let content = b"\x00\x01\r\n\n\xff".to_vec();
+        let config = Config {
+            default_transfer_type: Some("binary".to_owned()),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+
+        let mut data = client.pasv().await;
+        assert!(client.cmd("STOR data.bin").await.starts_with("125"));
+        data.write_all(&content).await.unwrap();
+        drop(data);
+        assert!(client.reply().await.starts_with("226"));
+
+        let mut data = client.pasv().await;
+        assert!(client.cmd("RETR data.bin").await.starts_with("125"));
+        let mut received = vec![];
+        data.read_to_end(&mut received).await.unwrap();
+        assert!(client.reply().await.starts_with("226"));
+        assert_eq!(received, content);
+    }
+
+    #[tokio::test]
+    async fn test_stat_reports_transferred_bytes() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("big.bin"), vec![7; 100_000]).unwrap();
+        let mut client = TestClient::login(root.path()).await;
+        assert!(client.cmd("STAT").await.starts_with("211-Status: 0 bytes"));
+
+        let mut data = client.pasv().await;
+        assert!(client.cmd("RETR big.bin").await.starts_with("125"));
+        let mut received = vec![];
+        data.read_to_end(&mut received).await.unwrap();
+        assert!(client.reply().await.starts_with("226"));
+        assert_eq!(received.len(), 100_000);
+        assert!(client.cmd("STAT").await.starts_with("211-Status: 100000 bytes"));
+
+        let mut data = client.pasv().await;
+        assert!(client.cmd("STOR small.bin").await.starts_with("125"));
+        data.write_all(&[1; 42]).await.unwrap();
+        drop(data);
+        assert!(client.reply().await.starts_with("226"));
+        assert!(client.cmd("STAT").await.starts_with("211-Status: 42 bytes"));
+    }
+
+    #[tokio::test]
+    async fn test_mkd_quotes_created_path() {
+        let root = tempfile::tempdir().unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        assert_eq!(client.cmd("MKD say\"hi\"").await, "257 \"/say\"\"hi\"\"\" created\r\n");
+        assert!(root.path().join("say\"hi\"").is_dir());
+        assert_eq!(client.cmd("PWD").await, "257 \"/\" is the current directory\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_list_glob() {
+        let root = tempfile::tempdir().unwrap();
+        for name in &["main.rs", "lib.rs", "notes.txt"] {
+            std::fs::write(root.path().join(name), b"").unwrap();
+        }
+        let mut client = TestClient::login(root.path()).await;
+
+        let mut data = client.pasv().await;
+        assert!(client.cmd("LIST *.rs").await.starts_with("125"));
+        let mut listing = String::new();
+        data.read_to_string(&mut listing).await.unwrap();
+        assert!(client.reply().await.starts_with("226"));
+        assert_eq!(listing.lines().count(), 2);
+        assert!(listing.contains(" main.rs\r\n"));
+        assert!(listing.contains(" lib.rs\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_nlst_glob() {
+        let root = tempfile::tempdir().unwrap();
+        for name in &["data1.bin", "data2.bin", "data10.bin", "other.bin"] {
+            std::fs::write(root.path().join(name), b"").unwrap();
+        }
+        let mut client = TestClient::login(root.path()).await;
+
+        let mut data = client.pasv().await;
+        assert!(client.cmd("NLST data?.bin").await.starts_with("125"));
+        let mut listing = String::new();
+        data.read_to_string(&mut listing).await.unwrap();
+        assert!(client.reply().await.starts_with("226"));
+        let mut names: Vec<&str> = listing.split_terminator("\r\n").collect();
+        names.sort();
+        assert_eq!(names, vec!["data1.bin", "data2.bin"]);
+    }
+
+    #[tokio::test]
+    async fn test_site_history() {
+        let root = tempfile::tempdir().unwrap();
+        let config = Config {
+            admin: Some(User {
+                name: "admin".to_owned(),
+                password: "secret".to_owned(),
+                ..Default::default()
+            }),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+        assert!(client.cmd("SITE HISTORY").await.starts_with("550"));
+
+        assert!(client.cmd("USER admin").await.starts_with("331"));
+        assert!(client.cmd("PASS secret").await.starts_with("230"));
+        assert!(client.cmd("NOOP").await.starts_with("200"));
+        assert!(client.cmd("PWD").await.starts_with("257"));
+
+        let history = client.cmd("SITE HISTORY").await;
+        let lines: Vec<&str> = history.lines().collect();
+        assert_eq!(lines[0], "200-Command history:");
+        assert_eq!(
+            &lines[lines.len() - 6..],
+            &[
+                " User(\"admin\")",
+                " Pass(<redacted>)",
+                " NoOp",
+                " Pwd",
+                " Site(\"HISTORY\")",
+                "200 End of history",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_site_reload() {
+        let root = tempfile::tempdir().unwrap();
+        let admin = "[admin]\nname = \"admin\"\npassword = \"\"\n";
+        std::fs::write(root.path().join("config.toml"), format!("users = []\n{}", admin)).unwrap();
+        let config = Config::new(root.path().join("config.toml")).unwrap();
+        let addr = start_server(root.path(), config).await;
+        let mut admin_client = TestClient::connect(addr).await;
+        assert!(admin_client.cmd("USER admin").await.starts_with("230"));
+
+        let mut client = TestClient::connect(addr).await;
+        assert!(client.cmd("USER crab").await.starts_with("530"));
+
+        let users = "[[users]]\nname = \"crab\"\npassword = \"\"\n";
+        std::fs::write(root.path().join("config.toml"), format!("{}{}", admin, users)).unwrap();
+        assert!(admin_client.cmd("SITE RELOAD").await.starts_with("200 Config reloaded"));
+        assert!(client.cmd("USER crab").await.starts_with("230"));
+
+        std::fs::write(root.path().join("config.toml"), "users = [").unwrap();
+        assert!(admin_client.cmd("SITE RELOAD").await.starts_with("451"));
+        let mut client = TestClient::connect(addr).await;
+        assert!(client.cmd("USER crab").await.starts_with("230"));
+    }
+
+    #[tokio::test]
+    async fn test_list_paths() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("sub/inner")).unwrap();
+        std::fs::write(root.path().join("top.txt"), b"").unwrap();
+        std::fs::write(root.path().join("sub/file.txt"), b"").unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        let top = vec!["sub/", "top.txt"];
+        let sub = vec!["file.txt", "inner/"];
+        assert_eq!(client.list("LIST").await, top);
+        assert_eq!(client.list("LIST .").await, top);
+        assert_eq!(client.list("LIST /").await, top);
+        assert_eq!(client.list("LIST sub").await, sub);
+        assert_eq!(client.list("LIST /sub").await, sub);
+        assert_eq!(client.list("LIST -la").await, top);
+        assert_eq!(client.list("LIST -l /sub").await, sub);
+
+        assert!(client.cmd("CWD sub").await.starts_with("250"));
+        assert_eq!(client.list("LIST").await, sub);
+        assert_eq!(client.list("LIST .").await, sub);
+        assert_eq!(client.list("LIST /").await, top);
+        assert_eq!(client.list("LIST inner").await, Vec::<String>::new());
+        assert_eq!(client.list("LIST /sub").await, sub);
+
+        assert!(client.cmd("CWD /").await.starts_with("250"));
+        assert!(client.cmd("PWD").await.starts_with("257 \"/\""));
+        assert_eq!(client.list("LIST").await, top);
+    }
+
+    #[tokio::test]
+    async fn test_list_large_directory() {
+        let root = tempfile::tempdir().unwrap();
+        for i in 0..3000 {
+            std::fs::write(root.path().join(format!("file{:04}.txt", i)), b"").unwrap();
+        }
+        let mut client = TestClient::login(root.path()).await;
+
+        let names = client.list("LIST").await;
+        assert_eq!(names.len(), 3000);
+        assert_eq!(names[0], "file0000.txt");
+        assert_eq!(names[2999], "file2999.txt");
+    }
+
+    #[tokio::test]
+    async fn test_login_timeout() {
+        let root = tempfile::tempdir().unwrap();
+        let config = Config {
+            login_timeout: Some(1),
+            ..test_config()
+        };
+        let addr = start_server(root.path(), config).await;
+
+        let mut idle = TestClient::connect(addr).await;
+        let mut logged = TestClient::connect(addr).await;
+        assert!(logged.cmd("USER ferris").await.starts_with("230"));
+
+        assert!(idle.reply().await.starts_with("421 Login timeout"));
+        assert_eq!(idle.reply().await, "");
+        assert!(logged.cmd("NOOP").await.starts_with("200"));
+    }
+
+    #[tokio::test]
+    async fn test_rest_stor_resumes_upload() {
+        let root = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..200).collect();
+        let addr = start_server(root.path(), test_config()).await;
+
+        let mut client = TestClient::connect(addr).await;
+        assert!(client.cmd("USER ferris").await.starts_with("230"));
+        assert!(client.cmd("FEAT").await.contains("\r\n REST STREAM\r\n"));
+        let mut data = client.pasv().await;
+        assert!(client.cmd("STOR resumed.bin").await.starts_with("125"));
+        data.write_all(&content[..100]).await.unwrap();
+        drop(data);
+        assert!(client.reply().await.starts_with("226"));
+        drop(client);
+
+        let mut client = TestClient::connect(addr).await;
+        assert!(client.cmd("USER ferris").await.starts_with("230"));
+        assert!(client.cmd("REST 100").await.starts_with("350"));
+        let mut data = client.pasv().await;
+        assert!(client.cmd("STOR resumed.bin").await.starts_with("125"));
+        data.write_all(&content[100..]).await.unwrap();
+        drop(data);
+        assert!(client.reply().await.starts_with("226"));
+        assert_eq!(std::fs::read(root.path().join("resumed.bin")).unwrap(), content);
+
+        assert!(client.cmd("REST 150").await.starts_with("350"));
+        let mut data = client.pasv().await;
+        assert!(client.cmd("RETR resumed.bin").await.starts_with("125"));
+        let mut received = vec![];
+        data.read_to_end(&mut received).await.unwrap();
+        assert!(client.reply().await.starts_with("226"));
+        assert_eq!(received, &content[150..]);
+    }
+
+    #[tokio::test]
+    async fn test_rmd_non_empty_and_site_rmdr() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("full/inner")).unwrap();
+        std::fs::create_dir(root.path().join("empty")).unwrap();
+        let config = Config {
+            admin: Some(User {
+                name: "admin".to_owned(),
+                password: "".to_owned(),
+                ..Default::default()
+            }),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+
+        assert!(client.cmd("RMD full").await.starts_with("550 Directory not empty"));
+        assert!(root.path().join("full/inner").is_dir());
+        assert!(client.cmd("RMD empty").await.starts_with("250"));
+        assert!(!root.path().join("empty").exists());
+        assert!(client.cmd("SITE RMDR full").await.starts_with("550"));
+
+        assert!(client.cmd("USER admin").await.starts_with("230"));
+        assert!(client.cmd("SITE RMDR /").await.starts_with("550"));
+        assert!(client.cmd("SITE RMDR full").await.starts_with("250"));
+        assert!(!root.path().join("full").exists());
+    }
+
+    #[tokio::test]
+    async fn test_hash() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("hello.txt"), b"hello world").unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        assert_eq!(
+            client.cmd("HASH hello.txt").await,
+            "213 SHA-256 0-11 b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9 /hello.txt\r\n"
+        );
+        assert_eq!(client.cmd("SITE HASH MD5").await, "200 MD5\r\n");
+        assert_eq!(
+            client.cmd("HASH hello.txt").await,
+            "213 MD5 0-11 5eb63bbbe01eeed093cb22bb8f5acdc3 /hello.txt\r\n"
+        );
+        assert_eq!(client.cmd("SITE HASH crc32").await, "200 CRC32\r\n");
+        assert_eq!(client.cmd("HASH hello.txt").await, "213 CRC32 0-11 0d4a1185 /hello.txt\r\n");
+        assert!(client.cmd("SITE HASH SHA-1").await.starts_with("501"));
+        assert!(client.cmd("HASH missing.txt").await.starts_with("550"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_umask() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = tempfile::tempdir().unwrap();
+        let config = Config {
+            umask: Some("027".to_owned()),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+
+        let mut data = client.pasv().await;
+        assert!(client.cmd("STOR masked.txt").await.starts_with("125"));
+        data.write_all(b"data").await.unwrap();
+        drop(data);
+        assert!(client.reply().await.starts_with("226"));
+        assert!(client.cmd("MKD masked").await.starts_with("257"));
+
+        let mode = |name: &str| std::fs::metadata(root.path().join(name)).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode("masked.txt"), 0o640);
+        assert_eq!(mode("masked"), 0o750);
+    }
+
+    #[tokio::test]
+    async fn test_account_lockout() {
+        let root = tempfile::tempdir().unwrap();
+        let mut config = test_config();
+        config.users.push(User {
+            name: "crab".to_owned(),
+            password: "secret".to_owned(),
+            ..Default::default()
+        });
+        config.max_login_failures = Some(3);
+        let addr = start_server(root.path(), config).await;
+
+        let mut client = TestClient::connect(addr).await;
+        assert!(client.cmd("USER crab").await.starts_with("331"));
+        for _ in 0..3 {
+            assert!(client.cmd("PASS guess").await.starts_with("530 Invalid password"));
+        }
+        assert!(client.cmd("PASS secret").await.starts_with("530 Account temporarily locked"));
+
+        let mut client = TestClient::connect(addr).await;
+        assert!(client.cmd("USER crab").await.starts_with("331"));
+        assert!(client.cmd("PASS secret").await.starts_with("530 Account temporarily locked"));
+        assert!(client.cmd("USER ferris").await.starts_with("230"));
+    }
+
+    #[tokio::test]
+    async fn test_multiple_listeners() {
+        let root = tempfile::tempdir().unwrap();
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addrs = [first.local_addr().unwrap(), second.local_addr().unwrap()];
+        tokio::spawn(serve(vec![first, second], root.path().canonicalize().unwrap(), test_config()));
+
+        for addr in &addrs {
+            let mut client = TestClient::connect(*addr).await;
+            assert!(client.cmd("USER ferris").await.starts_with("230"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pasv_bind_failure() {
+        let root = tempfile::tempdir().unwrap();
+        let busy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = busy.local_addr().unwrap().port();
+        let config = Config {
+            passive_port_range: Some((port, port)),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+
+        assert!(client.cmd("PASV").await.starts_with("425"));
+        assert!(client.cmd("NOOP").await.starts_with("200"));
+
+        drop(busy);
+        client.pasv().await;
+    }
+
+    #[tokio::test]
+    async fn test_pasv_send_failure_releases_port() {
+        use futures::StreamExt;
+        use std::sync::Arc;
+        use std::time::Duration;
+        use tokio_util::codec::Framed;
+
+        use super::{Client, Connection, ControlStream, Shared};
+        use crate::codec::FtpCodec;
+
+        let mut control = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = TcpStream::connect(control.local_addr().unwrap()).await.unwrap();
+        let (socket, addr) = control.accept().await.unwrap();
+        // Reset the control connection so that sending the 227 reply fails.
+        peer.set_linger(Some(Duration::from_secs(0))).unwrap();
+        drop(peer);
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+
+        let port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+        let shared = Arc::new(Shared::new(Config {
+            passive_port_range: Some((port, port)),
+            ..test_config()
+        }));
+        let socket: Box<dyn ControlStream> = Box::new(socket);
+        let (writer, reader) = Framed::new(socket, FtpCodec).split();
+        let connection = Connection { id: 1, addr };
+        let client = Client::new(writer, reader, connection, std::env::temp_dir(), shared);
+
+        assert!(client.pasv().await.is_err());
+        TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+    }
+
+    fn jail() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("a/b")).unwrap();
+        std::fs::write(root.path().join("a/file"), b"").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("/", root.path().join("escape")).unwrap();
+        root
+    }
+
+    // Whether `path` climbs above the directory it is resolved from, looking at
+    // its components only.
+    fn escapes_lexically(path: &Path) -> bool {
+        let mut depth = 0i32;
+        for component in path.components() {
+            match component {
+                Component::ParentDir => depth -= 1,
+                Component::Normal(_) => depth += 1,
+                _ => (),
+            }
+            if depth < 0 {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn check_confined(root: &Path, path: &str) {
+        let path = PathBuf::from(path);
+        if let Ok(resolved) = resolve_path(root, path.clone()) {
+            assert!(resolved.starts_with(root), "{:?} resolved to {:?}", path, resolved);
+        }
+        if let Ok(resolved) = resolve_path_allow_missing(root, path.clone()) {
+            assert!(resolved.starts_with(root), "{:?} resolved to {:?}", path, resolved);
+        }
+        if escapes_lexically(&path) {
+            assert!(invalid_path(&path), "{:?} wasn't rejected", path);
+        }
+    }
+
+    #[test]
+    fn test_path_confinement_known_cases() {
+        let root = jail();
+        let root = root.path().canonicalize().unwrap();
+        for path in &[
+            "a/../../etc",
+            "/../",
+            "./../",
+            "..",
+            "/..",
+            "a/b/../../..",
+            "/a/./b/../../../etc/passwd",
+            "escape",
+            "escape/etc",
+            "/escape/../etc",
+            "a/b/../b/./../../a",
+            "...",
+            "..a/..b",
+            "\\..\\..",
+        ] {
+            check_confined(&root, path);
+        }
+        assert!(resolve_path(&root, PathBuf::from("a/../../etc")).is_err());
+        assert!(invalid_path(Path::new("./../")));
+        #[cfg(unix)]
+        assert_eq!(
+            resolve_path(&root, PathBuf::from("escape/etc")).unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(resolve_path(&root, PathBuf::from("/a/b/..")).unwrap(), root.join("a"));
+    }
+
+    #[test]
+    fn test_resolve_path_allow_missing() {
+        let root = jail();
+        let root = root.path().canonicalize().unwrap();
+        assert_eq!(resolve_path_allow_missing(&root, PathBuf::from("/a/new")).unwrap(), root.join("a/new"));
+        assert_eq!(resolve_path_allow_missing(&root, PathBuf::from("a/b/x/y")).unwrap(), root.join("a/b/x/y"));
+        assert_eq!(resolve_path_allow_missing(&root, PathBuf::from("a/file")).unwrap(), root.join("a/file"));
+        assert!(resolve_path_allow_missing(&root, PathBuf::from("a/new/../../..")).is_err());
+        #[cfg(unix)]
+        {
+            assert!(resolve_path_allow_missing(&root, PathBuf::from("escape/new")).is_err());
+            std::os::unix::fs::symlink("/nonexistent-target", root.join("dangling")).unwrap();
+            assert!(resolve_path_allow_missing(&root, PathBuf::from("dangling")).is_err());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_path_confinement(
+            absolute in any::<bool>(),
+            segments in prop::collection::vec(
+                prop_oneof![
+                    Just("..".to_owned()),
+                    Just(".".to_owned()),
+                    Just("".to_owned()),
+                    Just("a".to_owned()),
+                    Just("b".to_owned()),
+                    Just("file".to_owned()),
+                    Just("escape".to_owned()),
+                    "[a-z.]{1,4}",
+                    "\\PC{1,8}",
+                ],
+                0..64,
+            ),
+        ) {
+            let root = jail();
+            let root = root.path().canonicalize().unwrap();
+            let path = format!("{}{}", if absolute { "/" } else { "" }, segments.join("/"));
+            check_confined(&root, &path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let root = tempfile::tempdir().unwrap();
+        let config = Config {
+            users: vec![User {
+                name: "ferris".to_owned(),
+                password: "crab".to_owned(),
+                ..Default::default()
+            }],
+            ..Config::default()
+        };
+        let addr = start_server(root.path(), config).await;
+        let mut client = TestClient::connect(addr).await;
+
+        assert!(client.user("ferris", "nope").await.starts_with("530"));
+        assert!(client.user("ferris", "crab").await.starts_with("230"));
+        assert_eq!(client.pwd().await, "/");
+        assert!(client.cmd("MKD docs").await.starts_with("257"));
+        client.cwd("docs").await;
+        assert_eq!(client.pwd().await, "/docs");
+        client.stor("notes.txt", b"hello").await;
+        assert_eq!(client.list("LIST").await, vec!["notes.txt"]);
+        assert_eq!(client.retr("notes.txt").await, b"hello");
+        assert_eq!(std::fs::read(root.path().join("docs/notes.txt")).unwrap(), b"hello");
+        assert!(client.cmd("QUIT").await.starts_with("221"));
+    }
+
+    #[tokio::test]
+    async fn test_opts_hash() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("hello.txt"), b"hello world").unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        assert!(client.cmd("FEAT").await.contains(" HASH SHA-256*;MD5;CRC32;\r\n"));
+        assert_eq!(client.cmd("OPTS HASH MD5").await, "200 MD5\r\n");
+        assert!(client.cmd("FEAT").await.contains(" HASH SHA-256;MD5*;CRC32;\r\n"));
+        assert_eq!(
+            client.cmd("HASH hello.txt").await,
+            "213 MD5 0-11 5eb63bbbe01eeed093cb22bb8f5acdc3 /hello.txt\r\n"
+        );
+        assert_eq!(client.cmd("OPTS HASH").await, "200 MD5\r\n");
+        assert!(client.cmd("OPTS HASH SHA-512").await.starts_with("501"));
+        assert!(client.cmd("OPTS FOO").await.starts_with("501"));
+        assert_eq!(client.cmd("OPTS HASH").await, "200 MD5\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let root = tempfile::tempdir().unwrap();
+        let config = Config {
+            idle_timeout: Some(1),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+
+        assert!(client.cmd("NOOP").await.starts_with("200"));
+        assert_eq!(client.reply().await, "421 Timeout - closing control connection\r\n");
+        assert_eq!(client.reply().await, "");
+    }
+
+    #[test]
+    fn test_connection_ids() {
+        use std::sync::atomic::Ordering;
+
+        use super::{Connection, Shared};
+
+        let shared = Shared::new(test_config());
+        let first = shared.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let second = shared.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        assert!(second > first);
+        let connection = Connection {
+            id: second,
+            addr: "127.0.0.1:4321".parse().unwrap(),
+        };
+        assert_eq!(connection.to_string(), "[#2 127.0.0.1:4321]");
+    }
+
+    #[tokio::test]
+    async fn test_size_keeps_restart_offset() {
+        let root = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        std::fs::write(root.path().join("data.bin"), &content).unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        assert!(client.cmd("REST 100").await.starts_with("350"));
+        assert_eq!(client.cmd("SIZE data.bin").await, "213 200\r\n");
+        assert_eq!(client.retr("data.bin").await, &content[100..]);
+
+        assert!(client.cmd("REST 100").await.starts_with("350"));
+        client.cwd("/").await;
+        assert_eq!(client.retr("data.bin").await, content);
+        assert!(client.cmd("SIZE missing.bin").await.starts_with("550"));
+    }
+
+    #[tokio::test]
+    async fn test_custom_authenticator() {
+        use std::sync::Arc;
+
+        use async_trait::async_trait;
+
+        use super::{serve_shared, Shared};
+        use crate::auth::{Authenticator, Permissions, UserInfo};
+
+        struct OnlyCrab;
+
+        #[async_trait]
+        impl Authenticator for OnlyCrab {
+            async fn authenticate(&self, user: &str, pass: &str) -> Option<UserInfo> {
+                if user == "crab" && pass == "rustacean" {
+                    Some(UserInfo {
+                        home: Some(PathBuf::from("home")),
+                        permissions: Permissions {
+                            write: false,
+                            ..Permissions::user()
+                        },
+                        quota: None,
+                        upload_dir: None,
+                        max_rate: None,
+                    })
+                } else {
+                    None
+                }
+            }
+        }
+
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("home")).unwrap();
+        std::fs::write(root.path().join("home/file.txt"), b"claws").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut shared = Shared::new(test_config());
+        shared.authenticator = Arc::new(OnlyCrab);
+        tokio::spawn(serve_shared(vec![(listener, None)], root.path().canonicalize().unwrap(), Arc::new(shared)));
+        let mut client = TestClient::connect(addr).await;
+
+        // The users of the config aren't used anymore.
+        assert!(client.user("ferris", "").await.starts_with("530"));
+        assert!(client.user("crab", "nope").await.starts_with("530"));
+        assert!(client.user("crab", "rustacean").await.starts_with("230"));
+        assert_eq!(client.pwd().await, "/home");
+        assert_eq!(client.retr("file.txt").await, b"claws");
+        assert!(client.cmd("MKD new").await.starts_with("550"));
+        assert!(!root.path().join("home/new").exists());
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_dropbox() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("incoming")).unwrap();
+        std::fs::write(root.path().join("public.txt"), b"hi").unwrap();
+        let config = Config {
+            users: vec![User {
+                name: "anonymous".to_owned(),
+                password: "".to_owned(),
+                ..Default::default()
+            }],
+            anonymous_dropbox: Some("incoming".to_owned()),
+            ..Config::default()
+        };
+        let addr = start_server(root.path(), config).await;
+        let mut client = TestClient::connect(addr).await;
+        assert!(client.cmd("USER anonymous").await.starts_with("230"));
+
+        client.stor("incoming/upload.txt", b"secret").await;
+        assert_eq!(std::fs::read(root.path().join("incoming/upload.txt")).unwrap(), b"secret");
+        assert!(client.cmd("RETR incoming/upload.txt").await.starts_with("550"));
+        assert!(client.cmd("RETR public.txt").await.starts_with("550"));
+        assert!(client.cmd("LIST incoming").await.starts_with("550"));
+        assert!(client.cmd("MKD incoming/dir").await.starts_with("550"));
+
+        let _data = client.pasv().await;
+        assert!(client.cmd("STOR elsewhere.txt").await.starts_with("550"));
+        assert!(!root.path().join("elsewhere.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_site_umask() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = tempfile::tempdir().unwrap();
+        let config = Config {
+            admin: Some(User {
+                name: "admin".to_owned(),
+                password: "".to_owned(),
+                ..Default::default()
+            }),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+        assert!(client.cmd("SITE UMASK 022").await.starts_with("550"));
+
+        assert!(client.cmd("USER admin").await.starts_with("230"));
+        assert_eq!(client.cmd("SITE UMASK").await, "200 No UMASK set\r\n");
+        assert_eq!(client.cmd("SITE UMASK 077").await, "200 UMASK set to 077\r\n");
+        assert_eq!(client.cmd("SITE UMASK").await, "200 Current UMASK is 077\r\n");
+        assert!(client.cmd("SITE UMASK 888").await.starts_with("501"));
+        assert!(client.cmd("SITE UMASK 1000").await.starts_with("501"));
+        client.stor("private.txt", b"data").await;
+        assert!(client.cmd("MKD private").await.starts_with("257"));
+
+        let mode = |name: &str| std::fs::metadata(root.path().join(name)).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode("private.txt"), 0o600);
+        assert_eq!(mode("private"), 0o700);
+    }
+
+    #[tokio::test]
+    async fn test_connection_rate_limit() {
+        let root = tempfile::tempdir().unwrap();
+        let config = Config {
+            connection_rate_limit: Some(2),
+            ..test_config()
+        };
+        let addr = start_server(root.path(), config).await;
+        let _first = TestClient::connect(addr).await;
+        let _second = TestClient::connect(addr).await;
+
+        let mut third = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        let mut reply = String::new();
+        third.read_line(&mut reply).await.unwrap();
+        assert_eq!(reply, "421 Too many connections from your address\r\n");
+        reply.clear();
+        assert_eq!(third.read_line(&mut reply).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_connection_rate_window() {
+        use super::Shared;
+
+        let shared = Shared::new(Config {
+            connection_rate_limit: Some(1),
+            connection_rate_window: Some(0),
+            ..test_config()
+        });
+        let ip = "127.0.0.1".parse().unwrap();
+        // With an empty window every connection has expired by the next one.
+        assert!(shared.allow_connection(ip));
+        assert!(shared.allow_connection(ip));
+        assert!(shared.connection_times.lock().unwrap().len() <= 1);
+
+        let shared = Shared::new(Config {
+            connection_rate_limit: Some(1),
+            ..test_config()
+        });
+        assert!(shared.allow_connection(ip));
+        assert!(!shared.allow_connection(ip));
+        assert!(shared.allow_connection("127.0.0.2".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_retr_data_connection_closed() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("big.bin"), vec![0; 32 * 1024 * 1024]).unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        let mut data = client.pasv().await;
+        assert!(client.cmd("RETR big.bin").await.starts_with("125"));
+        let mut buf = [0; 1024];
+        data.read_exact(&mut buf).await.unwrap();
+        data.set_linger(Some(std::time::Duration::from_secs(0))).unwrap();
+        drop(data);
+
+        assert_eq!(client.reply().await, "426 Connection closed; transfer aborted\r\n");
+        assert!(client.cmd("NOOP").await.starts_with("200"));
+        assert_eq!(client.retr("big.bin").await.len(), 32 * 1024 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_pasv_wait_is_not_idle() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("file.txt"), b"").unwrap();
+        let config = Config {
+            idle_timeout: Some(1),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+
+        let reply = client.cmd("PASV").await;
+        assert!(reply.starts_with("227"), "{}", reply);
+        let fields: Vec<u16> = reply[4..].trim().split(',').map(|field| field.parse().unwrap()).collect();
+        // Slower than the idle timeout to open the data connection.
+        tokio::time::delay_for(std::time::Duration::from_millis(1500)).await;
+        let mut data = TcpStream::connect(("127.0.0.1", fields[4] << 8 | fields[5])).await.unwrap();
+
+        assert!(client.cmd("LIST").await.starts_with("125"));
+        let mut listing = String::new();
+        data.read_to_string(&mut listing).await.unwrap();
+        assert!(listing.contains("file.txt"));
+        assert!(client.reply().await.starts_with("226"));
+    }
+
+    #[tokio::test]
+    async fn test_stor_connection_reset() {
+        let root = tempfile::tempdir().unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        let mut data = client.pasv().await;
+        assert!(client.cmd("STOR reset.bin").await.starts_with("125"));
+        data.write_all(&[0; 4096]).await.unwrap();
+        data.set_linger(Some(std::time::Duration::from_secs(0))).unwrap();
+        drop(data);
+
+        assert_eq!(client.reply().await, "426 Connection closed; transfer aborted\r\n");
+        assert!(!root.path().join("reset.bin").exists());
+        assert!(client.cmd("NOOP").await.starts_with("200"));
+    }
+
+    #[tokio::test]
+    async fn test_implicit_tls() {
+        use std::sync::Arc;
+
+        use tokio_rustls::rustls::{Certificate, ClientConfig};
+        use tokio_rustls::webpki::DNSNameRef;
+        use tokio_rustls::TlsConnector;
+
+        use super::{serve_shared, Shared};
+        use crate::tls;
+
+        let root = tempfile::tempdir().unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        std::fs::write(root.path().join("cert.pem"), cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(root.path().join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+        let acceptor = tls::acceptor(&root.path().join("cert.pem"), &root.path().join("key.pem"), None).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config {
+            login_timeout: Some(1),
+            ..test_config()
+        };
+        let shared = Arc::new(Shared::new(config));
+        tokio::spawn(serve_shared(vec![(listener, Some(acceptor))], root.path().canonicalize().unwrap(), shared));
+
+        let mut config = ClientConfig::new();
+        config.root_store.add(&Certificate(cert.serialize_der().unwrap())).unwrap();
+        let connector = TlsConnector::from(Arc::new(config));
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut control = BufReader::new(connector.connect(domain, stream).await.unwrap());
+
+        let mut reply = String::new();
+        control.read_line(&mut reply).await.unwrap();
+        assert!(reply.starts_with("220"), "{}", reply);
+        control.write_all(b"USER ferris\r\n").await.unwrap();
+        reply.clear();
+        control.read_line(&mut reply).await.unwrap();
+        assert!(reply.starts_with("230"), "{}", reply);
+
+        // Plain text connections to the TLS listener go nowhere.
+        let mut plain = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        plain.write_all(b"USER ferris\r\n").await.unwrap();
+        reply.clear();
+        assert!(plain.read_line(&mut reply).await.is_err() || !reply.starts_with("230"));
+
+        // Neither do the ones never starting the handshake.
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+        let read = tokio::time::timeout(std::time::Duration::from_secs(5), silent.read(&mut [0; 16])).await;
+        assert!(matches!(read, Ok(Ok(0))), "{:?}", read);
+    }
+
+    #[tokio::test]
+    async fn test_cwd_nested_path() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("sub/dir")).unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        client.cwd("/sub/dir").await;
+        assert_eq!(client.pwd().await, "/sub/dir");
+        client.cwd("/").await;
+        client.cwd("sub").await;
+        client.cwd("dir").await;
+        assert_eq!(client.pwd().await, "/sub/dir");
+    }
+
+    #[tokio::test]
+    async fn test_pwd_after_cwd() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("sub/dir")).unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        client.cwd("sub").await;
+        assert_eq!(client.cmd("PWD").await, "257 \"/sub\" is the current directory\r\n");
+        client.cwd("dir").await;
+        assert_eq!(client.cmd("PWD").await, "257 \"/sub/dir\" is the current directory\r\n");
+        assert!(client.cmd("CDUP").await.starts_with("200"));
+        assert_eq!(client.cmd("PWD").await, "257 \"/sub\" is the current directory\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_cwd_into_file() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("sub")).unwrap();
+        std::fs::write(root.path().join("sub/somefile.txt"), b"not a directory").unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        client.cwd("sub").await;
+        assert_eq!(client.cmd("CWD somefile.txt").await, "550 Not a directory\r\n");
+        assert_eq!(client.pwd().await, "/sub");
+        assert_eq!(client.list("LIST").await, ["somefile.txt"]);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_windows_paths() {
+        use super::{ftp_path, native_path};
+
+        assert_eq!(native_path(Path::new("/sub/dir")), PathBuf::from("\\sub\\dir"));
+        assert_eq!(ftp_path(Path::new("sub\\dir")), "sub/dir");
+    }
+
+    #[tokio::test]
+    async fn test_site_who() {
+        let root = tempfile::tempdir().unwrap();
+        let config = Config {
+            admin: Some(User {
+                name: "admin".to_owned(),
+                password: "".to_owned(),
+                ..Default::default()
+            }),
+            ..test_config()
+        };
+        let addr = start_server(root.path(), config).await;
+        let mut ferris = TestClient::connect(addr).await;
+        assert!(ferris.cmd("USER ferris").await.starts_with("230"));
+        let mut admin = TestClient::connect(addr).await;
+        assert!(admin.cmd("SITE WHO").await.starts_with("530"));
+        assert!(admin.cmd("USER admin").await.starts_with("230"));
+
+        let who = admin.cmd("SITE WHO").await;
+        let lines: Vec<&str> = who.lines().collect();
+        assert_eq!(lines.len(), 4, "{}", who);
+        assert_eq!(lines[0], "200-Connected users:");
+        assert!(lines[1].starts_with(" #1 ferris 127.0.0.1:"), "{}", who);
+        assert!(lines[1].contains(" idle "), "{}", who);
+        assert!(lines[2].starts_with(" #2 admin 127.0.0.1:"), "{}", who);
+        assert!(lines[2].contains(" SITE "), "{}", who);
+        assert_eq!(lines[3], "200 End of list");
+
+        // The session goes away with the connection.
+        assert!(ferris.cmd("QUIT").await.starts_with("221"));
+        let mut gone = false;
+        for _ in 0..50 {
+            if !admin.cmd("SITE WHO").await.contains(" ferris ") {
+                gone = true;
+                break;
+            }
+            tokio::time::delay_for(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(gone);
+    }
+
+    #[tokio::test]
+    async fn test_read_only() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("dir/sub")).unwrap();
+        std::fs::write(root.path().join("file.txt"), b"data").unwrap();
+        let config = Config {
+            read_only: Some(true),
+            admin: Some(User {
+                name: "admin".to_owned(),
+                password: "".to_owned(),
+                ..Default::default()
+            }),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+        assert!(client.cmd("USER admin").await.starts_with("230"));
+
+        let _data = client.pasv().await;
+        assert_eq!(client.cmd("STOR file.txt").await, "550 Read-only server\r\n");
+        assert_eq!(client.cmd("STOR new.txt").await, "550 Read-only server\r\n");
+        assert_eq!(client.cmd("MKD new").await, "550 Read-only server\r\n");
+        assert_eq!(client.cmd("RMD dir/sub").await, "550 Read-only server\r\n");
+        assert_eq!(client.cmd("SITE RMDR dir").await, "550 Read-only server\r\n");
+
+        assert_eq!(std::fs::read(root.path().join("file.txt")).unwrap(), b"data");
+        assert!(!root.path().join("new.txt").exists());
+        assert!(!root.path().join("new").exists());
+        assert!(root.path().join("dir/sub").is_dir());
+        assert_eq!(client.retr("file.txt").await, b"data");
+    }
+
+    #[tokio::test]
+    async fn test_site_kick() {
+        let root = tempfile::tempdir().unwrap();
+        let config = Config {
+            admin: Some(User {
+                name: "admin".to_owned(),
+                password: "".to_owned(),
+                ..Default::default()
+            }),
+            ..test_config()
+        };
+        let addr = start_server(root.path(), config).await;
+        let mut ferris = TestClient::connect(addr).await;
+        assert!(ferris.cmd("USER ferris").await.starts_with("230"));
+        assert!(ferris.cmd("SITE KICK 2").await.starts_with("550"));
+        let mut admin = TestClient::connect(addr).await;
+        assert!(admin.cmd("USER admin").await.starts_with("230"));
+
+        assert!(admin.cmd("SITE WHO").await.contains(" #1 ferris "));
+        assert_eq!(admin.cmd("SITE KICK 1").await, "200 Kicked\r\n");
+        assert_eq!(ferris.reply().await, "421 Kicked by administrator\r\n");
+        assert_eq!(ferris.reply().await, "");
+        assert_eq!(admin.cmd("SITE KICK 1").await, "550 No such session\r\n");
+        assert_eq!(admin.cmd("SITE KICK nobody").await, "550 No such session\r\n");
+        assert!(admin.cmd("NOOP").await.starts_with("200"));
+    }
+
+    #[tokio::test]
+    async fn test_size_ascii() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("text.txt"), b"one\ntwo\r\nthree\n").unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        assert!(client.cmd("TYPE A").await.starts_with("200"));
+        assert_eq!(client.cmd("SIZE text.txt").await, "213 15\r\n");
+        assert_eq!(client.retr("text.txt").await.len(), 15);
+        assert!(client.cmd("TYPE I").await.starts_with("200"));
+        assert_eq!(client.cmd("SIZE text.txt").await, "213 15\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_port() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("file.txt"), b"active").unwrap();
+        let config = Config {
+            active_connect_timeout: Some(1),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let reply = client.cmd(&format!("PORT 127,0,0,1,{},{}", port >> 8, port & 0xFF)).await;
+        assert!(reply.starts_with("200"), "{}", reply);
+        let (mut data, _) = listener.accept().await.unwrap();
+        assert!(client.cmd("RETR file.txt").await.starts_with("125"));
+        let mut content = Vec::new();
+        data.read_to_end(&mut content).await.unwrap();
+        assert_eq!(content, b"active");
+        assert!(client.reply().await.starts_with("226"));
+
+        // Nobody listens there anymore.
+        drop(listener);
+        let reply = client.cmd(&format!("PORT 127,0,0,1,{},{}", port >> 8, port & 0xFF)).await;
+        assert_eq!(reply, "425 Can't open data connection\r\n");
+        assert!(client.cmd("NOOP").await.starts_with("200"));
+
+        // No bouncing to another host.
+        let listener = TcpListener::bind("127.0.0.2:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let reply = client.cmd(&format!("PORT 127,0,0,2,{},{}", port >> 8, port & 0xFF)).await;
+        assert_eq!(reply, "501 PORT must give the client's own address\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_empty_line() {
+        let root = tempfile::tempdir().unwrap();
+        let addr = start_server(root.path(), test_config()).await;
+        let mut client = TestClient::connect(addr).await;
+
+        assert!(client.cmd("").await.starts_with("200"));
+        assert!(client.cmd("USER ferris").await.starts_with("230"));
+        assert!(client.cmd("").await.starts_with("200"));
+        assert_eq!(client.pwd().await, "/");
+    }
+
+    #[tokio::test]
+    async fn test_max_rate() {
+        use std::time::Instant;
+
+        let root = tempfile::tempdir().unwrap();
+        let content = vec![b'x'; 40_000];
+        std::fs::write(root.path().join("data.bin"), &content).unwrap();
+        let mut config = test_config();
+        config.max_rate_bytes_per_sec = Some(20_000);
+        config.users.push(User {
+            name: "fast".to_owned(),
+            password: "".to_owned(),
+            max_rate_bytes_per_sec: Some(0),
+            ..Default::default()
+        });
+        let addr = start_server(root.path(), config).await;
+
+        let mut slow = TestClient::connect(addr).await;
+        assert!(slow.cmd("USER ferris").await.starts_with("230"));
+        let start = Instant::now();
+        assert_eq!(slow.retr("data.bin").await, content);
+        assert!(start.elapsed() >= std::time::Duration::from_millis(1500));
+
+        let mut fast = TestClient::connect(addr).await;
+        assert!(fast.cmd("USER fast").await.starts_with("230"));
+        let start = Instant::now();
+        assert_eq!(fast.retr("data.bin").await, content);
+        assert!(start.elapsed() < std::time::Duration::from_millis(1000));
+    }
+
+    #[tokio::test]
+    async fn test_noop_during_transfer() {
+        let root = tempfile::tempdir().unwrap();
+        let content = vec![b'x'; 250_000];
+        std::fs::write(root.path().join("data.bin"), &content).unwrap();
+        let config = Config {
+            max_rate_bytes_per_sec: Some(100_000),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+
+        let mut data = client.pasv().await;
+        assert!(client.cmd("RETR data.bin").await.starts_with("125"));
+        assert_eq!(client.cmd("NOOP").await, "200 Doing nothing\r\n");
+        assert!(client.cmd("STAT").await.starts_with("211-Status: "));
+        // Only answered once the transfer is over.
+        client.send("PWD").await;
+        let mut received = vec![];
+        data.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, content);
+        assert!(client.reply().await.starts_with("226"));
+        assert!(client.reply().await.starts_with("257"));
+    }
+
+    #[tokio::test]
+    async fn test_too_many_pending_commands() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("data.bin"), vec![b'x'; 250_000]).unwrap();
+        let config = Config {
+            max_rate_bytes_per_sec: Some(100_000),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+
+        let _data = client.pasv().await;
+        assert!(client.cmd("RETR data.bin").await.starts_with("125"));
+        for _ in 0..=MAX_PENDING_COMMANDS {
+            client.send("PWD").await;
+        }
+        assert_eq!(client.reply().await, "421 Too many commands - closing control connection\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_stat_parameters() {
+        let root = tempfile::tempdir().unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        assert!(client.cmd("TYPE I").await.starts_with("200"));
+        assert!(client.cmd("MODE S").await.starts_with("200"));
+        assert!(client.cmd("STRU F").await.starts_with("200"));
+        assert!(client.cmd("MODE B").await.starts_with("504"));
+        assert!(client.cmd("STRU R").await.starts_with("504"));
+        assert_eq!(
+            client.cmd("STAT").await,
+            "211-Status: 0 bytes transferred\r\n TYPE: Image\r\n MODE: Stream\r\n STRU: File\r\n211 End\r\n"
+        );
+        let features = client.cmd("FEAT").await;
+        assert!(features.contains("\r\n TYPE A;I\r\n MODE S\r\n STRU F\r\n"), "{}", features);
+    }
+
+    #[tokio::test]
+    async fn test_abor() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("data.bin"), vec![b'x'; 500_000]).unwrap();
+        let config = Config {
+            max_rate_bytes_per_sec: Some(100_000),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+
+        assert_eq!(client.cmd("ABOR").await, "226 Abort command successful\r\n");
+
+        let _data = client.pasv().await;
+        assert!(client.cmd("RETR data.bin").await.starts_with("125"));
+        assert_eq!(client.cmd("ABOR").await, "426 Connection closed; transfer aborted\r\n");
+        assert_eq!(client.reply().await, "226 Abort command successful\r\n");
+
+        let mut data = client.pasv().await;
+        assert!(client.cmd("STOR upload.bin").await.starts_with("125"));
+        data.write_all(b"partial").await.unwrap();
+        assert_eq!(client.cmd("ABOR").await, "426 Connection closed; transfer aborted\r\n");
+        assert_eq!(client.reply().await, "226 Abort command successful\r\n");
+        assert!(!root.path().join("upload.bin").exists());
+        assert!(client.cmd("NOOP").await.starts_with("200"));
+    }
+
+    #[tokio::test]
+    async fn test_stor_config_validated() {
+        let root = tempfile::tempdir().unwrap();
+        let original = "[[users]]\nname = \"ferris\"\npassword = \"\"\n";
+        std::fs::write(root.path().join("config.toml"), original).unwrap();
+        let config = Config {
+            admin: Some(User {
+                name: "admin".to_owned(),
+                password: "".to_owned(),
+                ..Default::default()
+            }),
+            ..test_config()
+        };
+        let addr = start_server(root.path(), config).await;
+        let mut client = TestClient::connect(addr).await;
+        assert!(client.cmd("USER admin").await.starts_with("230"));
+
+        let mut data = client.pasv().await;
+        assert!(client.cmd("STOR config.toml").await.starts_with("125"));
+        data.write_all(b"users = [").await.unwrap();
+        drop(data);
+        assert_eq!(client.reply().await, "550 Invalid config\r\n");
+        assert_eq!(std::fs::read_to_string(root.path().join("config.toml")).unwrap(), original);
+
+        // Parses, but fails validation.
+        let duplicate = "[[users]]\nname = \"crab\"\npassword = \"\"\n".repeat(2);
+        let mut data = client.pasv().await;
+        assert!(client.cmd("STOR config.toml").await.starts_with("125"));
+        data.write_all(duplicate.as_bytes()).await.unwrap();
+        drop(data);
+        assert_eq!(client.reply().await, "550 Invalid config\r\n");
+        assert_eq!(std::fs::read_to_string(root.path().join("config.toml")).unwrap(), original);
+
+        let updated = "[[users]]\nname = \"crab\"\npassword = \"\"\n";
+        client.stor("config.toml", updated.as_bytes()).await;
+        assert_eq!(std::fs::read_to_string(root.path().join("config.toml")).unwrap(), updated);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_commands() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("a.txt"), b"").unwrap();
+        std::fs::write(root.path().join("b.txt"), b"").unwrap();
+        let mut client = TestClient::login(root.path()).await;
+        assert!(client.cmd("HELP").await.contains(" DELE "));
+        assert!(client.cmd("DELE a.txt").await.starts_with("250"));
+        assert!(!root.path().join("a.txt").exists());
+        assert!(client.cmd("DELE a.txt").await.starts_with("550"));
+
+        let config = Config {
+            disabled_commands: Some(vec!["dele".to_owned(), "HASH".to_owned()]),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+        assert_eq!(client.cmd("DELE b.txt").await, "502 Command disabled\r\n");
+        assert!(root.path().join("b.txt").exists());
+        let help = client.cmd("HELP").await;
+        assert!(help.starts_with("214-"), "{}", help);
+        assert!(!help.contains("DELE") && !help.contains("HASH"), "{}", help);
+        let features = client.cmd("FEAT").await;
+        assert!(!features.contains("HASH"), "{}", features);
+        assert!(client.cmd("NOOP").await.starts_with("200"));
+    }
+
+    #[tokio::test]
+    async fn test_retr_slow_reader() {
+        let root = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..32 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        std::fs::write(root.path().join("big.bin"), &content).unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        let mut data = client.pasv().await;
+        assert!(client.cmd("RETR big.bin").await.starts_with("125"));
+        // The client doesn't read: the server has to wait for it, and still
+        // answers on the control connection meanwhile.
+        tokio::time::delay_for(std::time::Duration::from_millis(500)).await;
+        let status = client.cmd("STAT").await;
+        let sent: u64 = status["211-Status: ".len()..].split(' ').next().unwrap().parse().unwrap();
+        assert!(sent < 16 * 1024 * 1024, "{}", status);
+
+        let mut received = vec![];
+        data.read_to_end(&mut received).await.unwrap();
+        assert!(received == content);
+        assert!(client.reply().await.starts_with("226"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_special_files() {
+        let root = tempfile::tempdir().unwrap();
+        let fifo = root.path().join("fifo");
+        assert!(std::process::Command::new("mkfifo").arg(&fifo).status().unwrap().success());
+        std::fs::create_dir(root.path().join("dir")).unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        let _data = client.pasv().await;
+        assert_eq!(client.cmd("RETR fifo").await, "550 Not a regular file\r\n");
+        let _data = client.pasv().await;
+        assert_eq!(client.cmd("STOR fifo").await, "550 Not a regular file\r\n");
+        let _data = client.pasv().await;
+        assert_eq!(client.cmd("RETR dir").await, "550 Not a regular file\r\n");
+        assert!(client.cmd("NOOP").await.starts_with("200"));
+    }
+
+    #[tokio::test]
+    async fn test_site_quota() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("sub")).unwrap();
+        std::fs::write(root.path().join("sub/used.bin"), vec![0; 600]).unwrap();
+        // Outside of the home, not counted.
+        std::fs::write(root.path().join("other.bin"), vec![0; 600]).unwrap();
+        let mut config = test_config();
+        config.users[0].quota = Some(1000);
+        config.users[0].home = Some("sub".to_owned());
+        config.users.push(User {
+            name: "crab".to_owned(),
+            password: "".to_owned(),
+            ..Default::default()
+        });
+        config.admin = Some(User {
+            name: "admin".to_owned(),
+            password: "".to_owned(),
+            ..Default::default()
+        });
+        let addr = start_server(root.path(), config).await;
+
+        let mut client = TestClient::connect(addr).await;
+        assert!(client.cmd("USER ferris").await.starts_with("230"));
+        assert_eq!(client.cmd("SITE QUOTA").await, "200 Usage: 600 / 1000 bytes\r\n");
+        assert!(client.cmd("SITE QUOTA crab").await.starts_with("550"));
+        client.stor("small.bin", &[0; 300]).await;
+        assert_eq!(client.cmd("SITE QUOTA").await, "200 Usage: 900 / 1000 bytes\r\n");
+        let mut data = client.pasv().await;
+        assert!(client.cmd("STOR big.bin").await.starts_with("125"));
+        data.write_all(&[0; 200]).await.unwrap();
+        drop(data);
+        assert_eq!(client.reply().await, "552 Quota exceeded\r\n");
+        assert!(!root.path().join("sub/big.bin").exists());
+        // Refused as soon as the quota is exceeded, not once all is received.
+        let mut data = client.pasv().await;
+        assert!(client.cmd("STOR huge.bin").await.starts_with("125"));
+        tokio::spawn(async move {
+            let _ = data.write_all(&vec![0; 10_000_000]).await;
+        });
+        assert_eq!(client.reply().await, "552 Quota exceeded\r\n");
+        assert!(!root.path().join("sub/huge.bin").exists());
+        // Replacing a file only counts the difference.
+        client.stor("small.bin", &[0; 400]).await;
+
+        let mut admin = TestClient::connect(addr).await;
+        assert!(admin.cmd("USER admin").await.starts_with("230"));
+        assert_eq!(admin.cmd("SITE QUOTA").await, "200 No quota configured\r\n");
+        assert_eq!(admin.cmd("SITE QUOTA ferris").await, "200 Usage: 1000 / 1000 bytes\r\n");
+        assert!(admin.cmd("SITE QUOTA nobody").await.starts_with("550"));
+    }
+
+    #[tokio::test]
+    async fn test_create_nested_paths() {
+        let root = tempfile::tempdir().unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        assert!(client.cmd("MKD a").await.starts_with("257"));
+        assert!(client.cmd("MKD a/b").await.starts_with("257"));
+        assert!(client.cmd("MKD /a/b/c").await.starts_with("257"));
+        assert!(root.path().join("a/b/c").is_dir());
+        // The parent has to exist.
+        assert!(client.cmd("MKD x/y").await.starts_with("550"));
+        assert!(client.cmd("MKD a/../../outside").await.starts_with("550"));
+
+        client.stor("a/b/c/file.txt", b"nested").await;
+        assert_eq!(std::fs::read(root.path().join("a/b/c/file.txt")).unwrap(), b"nested");
+        client.cwd("a/b").await;
+        client.stor("c/other.txt", b"relative").await;
+        assert_eq!(std::fs::read(root.path().join("a/b/c/other.txt")).unwrap(), b"relative");
+        let _data = client.pasv().await;
+        assert!(client.cmd("STOR missing/file.txt").await.starts_with("550"));
+        assert!(client.cmd("NOOP").await.starts_with("200"));
+    }
+
+    #[tokio::test]
+    async fn test_socket_options() {
+        use super::SocketOptions;
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+
+        SocketOptions::new(&test_config()).apply(&socket).unwrap();
+        assert!(socket.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap().is_some());
+        let config = Config {
+            tcp_keepalive: Some(0),
+            tcp_nodelay: Some(false),
+            socket_send_buffer: Some(256 * 1024),
+            ..test_config()
+        };
+        SocketOptions::new(&config).apply(&socket).unwrap();
+        assert!(!socket.nodelay().unwrap());
+        assert_eq!(socket.keepalive().unwrap(), None);
+        // Linux doubles the requested size for its bookkeeping.
+        assert!(socket.send_buffer_size().unwrap() >= 256 * 1024);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_pasv_foreign_peer() {
+        use std::net::{Ipv4Addr, SocketAddr};
+        use std::os::unix::io::FromRawFd;
+
+        use nix::sys::socket::{bind, connect, socket, AddressFamily, InetAddr, SockAddr, SockFlag, SockType};
+
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("file.txt"), b"secret").unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        let reply = client.cmd("PASV").await;
+        let fields: Vec<u16> = reply[4..].trim().split(',').map(|field| field.parse().unwrap()).collect();
+        let port = fields[4] << 8 | fields[5];
+        // Connect from another loopback address than the control connection.
+        let fd = socket(AddressFamily::Inet, SockType::Stream, SockFlag::empty(), None).unwrap();
+        let source = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 2).into(), 0);
+        bind(fd, &SockAddr::new_inet(InetAddr::from_std(&source))).unwrap();
+        let target = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+        connect(fd, &SockAddr::new_inet(InetAddr::from_std(&target))).unwrap();
+        let _thief = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+
+        assert_eq!(client.reply().await, "425 Can't open data connection\r\n");
+        assert!(client.cmd("RETR file.txt").await.starts_with("426"));
+        assert_eq!(client.retr("file.txt").await, b"secret");
+    }
+
+    #[tokio::test]
+    async fn test_site_idle() {
+        let root = tempfile::tempdir().unwrap();
+        let config = Config {
+            idle_timeout: Some(60),
+            max_idle_timeout: Some(120),
+            admin: Some(User {
+                name: "admin".to_owned(),
+                password: "".to_owned(),
+                ..Default::default()
+            }),
+            ..test_config()
+        };
+        let addr = start_server(root.path(), config).await;
+        let mut ferris = TestClient::connect(addr).await;
+        assert!(ferris.cmd("USER ferris").await.starts_with("230"));
+        assert_eq!(ferris.cmd("SITE IDLE").await, "200 Current IDLE time limit is 60 seconds; max 120\r\n");
+        assert_eq!(ferris.cmd("SITE IDLE 1").await, "550 Permission denied\r\n");
+        assert!(ferris.cmd("SITE IDLE").await.starts_with("200 Current IDLE time limit is 60 seconds"));
+
+        let mut client = TestClient::connect(addr).await;
+        assert!(client.cmd("USER admin").await.starts_with("230"));
+        assert!(client.cmd("SITE IDLE 121").await.starts_with("501"));
+        assert!(client.cmd("SITE IDLE 0").await.starts_with("501"));
+        assert!(client.cmd("SITE IDLE soon").await.starts_with("501"));
+        assert_eq!(client.cmd("SITE IDLE 1").await, "200 Maximum IDLE time set to 1 seconds\r\n");
+        assert!(client.cmd("SITE IDLE").await.starts_with("200 Current IDLE time limit is 1 seconds"));
+        assert_eq!(client.reply().await, "421 Timeout - closing control connection\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_site_msg() {
+        let root = tempfile::tempdir().unwrap();
+        let config = Config {
+            admin: Some(User {
+                name: "admin".to_owned(),
+                password: "".to_owned(),
+                ..Default::default()
+            }),
+            ..test_config()
+        };
+        let addr = start_server(root.path(), config).await;
+        let mut ferris = TestClient::connect(addr).await;
+        assert!(ferris.cmd("USER ferris").await.starts_with("230"));
+        assert!(ferris.cmd("SITE MSG hello").await.starts_with("550"));
+        let mut admin = TestClient::connect(addr).await;
+        assert!(admin.cmd("USER admin").await.starts_with("230"));
+
+        assert!(admin.cmd("SITE MSG").await.starts_with("501"));
+        assert_eq!(admin.cmd("SITE MSG Server restarting in 5 minutes").await, "200 Message sent to 1 clients\r\n");
+        assert!(admin.cmd("SITE MSG Back in a minute").await.starts_with("200"));
+        // Delivered on their own to the idle session.
+        assert_eq!(ferris.reply().await, "220 Server restarting in 5 minutes\r\n");
+        assert_eq!(ferris.reply().await, "220 Back in a minute\r\n");
+        assert_eq!(ferris.cmd("NOOP").await, "200 Doing nothing\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_utf8_names() {
+        let root = tempfile::tempdir().unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        // Stored decomposed, as macOS clients upload them.
+        std::fs::write(root.path().join("e\u{301}te\u{301}.txt"), b"summer").unwrap();
+        assert_eq!(client.cmd("SIZE e\u{301}te\u{301}.txt").await, "213 6\r\n");
+        assert!(client.cmd("SIZE \u{e9}t\u{e9}.txt").await.starts_with("550"));
+
+        assert_eq!(client.cmd("OPTS UTF8 ON").await, "200 Always in UTF8 mode\r\n");
+        assert!(client.cmd("OPTS UTF8 OFF").await.starts_with("504"));
+        assert_eq!(client.cmd("SIZE \u{e9}t\u{e9}.txt").await, "213 6\r\n");
+        assert_eq!(client.retr("\u{e9}t\u{e9}.txt").await, b"summer");
+        // New names are created as typed.
+        assert!(client.cmd("MKD Cafe\u{301}").await.starts_with("257"));
+        assert!(root.path().join("Cafe\u{301}").is_dir());
+        assert!(client.cmd("CWD Caf\u{e9}").await.starts_with("250"));
+        client.stor("\u{e9}t\u{e9}.txt", b"winter").await;
+        assert_eq!(std::fs::read(root.path().join("Cafe\u{301}/\u{e9}t\u{e9}.txt")).unwrap(), b"winter");
+    }
+
+    #[tokio::test]
+    async fn test_abor_list() {
+        let root = tempfile::tempdir().unwrap();
+        for i in 0..2_000 {
+            std::fs::write(root.path().join(format!("{:0>100}", i)), b"").unwrap();
+        }
+        let mut client = TestClient::login(root.path()).await;
+
+        // Sent together so ABOR is waiting before the first chunk goes out.
+        let _data = client.pasv().await;
+        client.send("LIST\r\nABOR").await;
+        assert!(client.reply().await.starts_with("125"));
+        assert_eq!(client.reply().await, "426 Connection closed; transfer aborted\r\n");
+        assert_eq!(client.reply().await, "226 Abort command successful\r\n");
+
+        assert!(client.cmd("NOOP").await.starts_with("200"));
+        assert_eq!(client.list("NLST").await.len(), 2_000);
+    }
+
+    #[tokio::test]
+    async fn test_list_ignores_type() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("a.txt"), b"a\n").unwrap();
+        std::fs::create_dir(root.path().join("dir")).unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        for cmd in ["LIST", "NLST"].iter() {
+            assert!(client.cmd("TYPE A").await.starts_with("200"));
+            let ascii = client.transfer(cmd).await;
+            assert!(client.cmd("TYPE I").await.starts_with("200"));
+            let image = client.transfer(cmd).await;
+            assert_eq!(ascii, image);
+            let text = String::from_utf8(ascii).unwrap();
+            assert_eq!(text.matches("\r\n").count(), 2, "{:?}", text);
+            assert_eq!(text.matches('\n').count(), 2, "{:?}", text);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recursive_mkd() {
+        let root = tempfile::tempdir().unwrap();
+        let config = Config {
+            recursive_mkd: Some(true),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+
+        assert_eq!(client.cmd("MKD a/b/c").await, "257 \"/a/b/c\" created\r\n");
+        assert!(root.path().join("a/b/c").is_dir());
+        assert!(client.cmd("MKD a/b/c").await.starts_with("550"));
+        assert!(client.cmd("MKD a/b/d").await.starts_with("257"));
+        assert!(client.cmd("MKD x/../../outside").await.starts_with("550"));
+        assert!(!root.path().join("x").exists());
+    }
+
+    #[tokio::test]
+    async fn test_list_missing_directory() {
+        let root = tempfile::tempdir().unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        let _data = client.pasv().await;
+        assert_eq!(client.cmd("LIST /does/not/exist").await, "550 No such file or directory\r\n");
+        let _data = client.pasv().await;
+        assert_eq!(client.cmd("NLST nope").await, "550 No such file or directory\r\n");
+        assert!(client.cmd("NOOP").await.starts_with("200"));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol() {
+        let root = tempfile::tempdir().unwrap();
+        let config = Config {
+            admin: Some(User {
+                name: "admin".to_owned(),
+                password: "".to_owned(),
+                ..Default::default()
+            }),
+            accept_proxy_protocol: Some(true),
+            ..test_config()
+        };
+        let addr = start_server(root.path(), config).await;
+        let mut client = TestClient::connect_proxied(addr, "PROXY TCP4 192.0.2.10 127.0.0.1 56324 21").await;
+        assert!(client.cmd("USER admin").await.starts_with("230"));
+        assert!(client.cmd("SITE WHO").await.contains(" #1 admin 192.0.2.10:56324 "));
+
+        // No header: the connection gets dropped without a greeting.
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"USER ferris\r\n").await.unwrap();
+        let mut received = vec![];
+        stream.read_to_end(&mut received).await.unwrap();
+        assert!(received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_motd() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("motd.txt"), "Hello %u!\n\nUploads are scanned.\n").unwrap();
+        let config = Config {
+            motd: Some("motd.txt".to_owned()),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config.clone()).await;
+        assert_eq!(
+            client.cmd("USER ferris").await,
+            "230-Hello ferris!\r\n\r\nUploads are scanned.\r\n230 Welcome ferris!\r\n"
+        );
+
+        std::fs::remove_file(root.path().join("motd.txt")).unwrap();
+        let mut client = TestClient::login_with(root.path(), config).await;
+        assert_eq!(client.cmd("USER ferris").await, "230 Welcome ferris!\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_greeting_delay() {
+        let root = tempfile::tempdir().unwrap();
+        let config = Config {
+            greeting_delay_ms: Some(300),
+            ..test_config()
+        };
+        let addr = start_server(root.path(), config).await;
+
+        let start = std::time::Instant::now();
+        let (first, second) = futures::future::join(TestClient::connect(addr), TestClient::connect(addr)).await;
+        // Both connections wait, but not one after the other.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= std::time::Duration::from_millis(300), "{:?}", elapsed);
+        assert!(elapsed < std::time::Duration::from_millis(600), "{:?}", elapsed);
+        drop((first, second));
+    }
+
+    #[tokio::test]
+    async fn test_strict_ascii() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("image.png"), b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+        std::fs::write(root.path().join("text.txt"), b"hello\n").unwrap();
+        let config = Config {
+            strict_ascii: Some(true),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+
+        assert!(client.cmd("TYPE A").await.starts_with("200"));
+        let _data = client.pasv().await;
+        assert_eq!(client.cmd("RETR image.png").await, "550 File is binary; use TYPE I\r\n");
+        assert_eq!(client.retr("text.txt").await, b"hello\n");
+        assert!(client.cmd("TYPE I").await.starts_with("200"));
+        assert_eq!(client.retr("image.png").await, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        use super::{serve_shared, shutdown, Shared};
+        use std::sync::Arc;
+
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("data.bin"), vec![b'x'; 300_000]).unwrap();
+        let config = Config {
+            max_rate_bytes_per_sec: Some(100_000),
+            shutdown_grace_period: Some(1),
+            ..test_config()
+        };
+        let shared = Arc::new(Shared::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_root = root.path().canonicalize().unwrap();
+        tokio::spawn(serve_shared(vec![(listener, None)], server_root, shared.clone()));
+
+        let mut idle = TestClient::connect(addr).await;
+        assert!(idle.cmd("USER ferris").await.starts_with("230"));
+        let mut busy = TestClient::connect(addr).await;
+        assert!(busy.cmd("USER ferris").await.starts_with("230"));
+        let _data = busy.pasv().await;
+        assert!(busy.cmd("RETR data.bin").await.starts_with("125"));
+
+        // The download takes 3 seconds, more than the grace period.
+        assert_eq!(shutdown(&shared).await, 1);
+        assert_eq!(idle.reply().await, "421 Service shutting down\r\n");
+        assert_eq!(idle.reply().await, "");
+        assert!(busy.cmd("NOOP").await.starts_with("200"));
+    }
+
+    #[tokio::test]
+    async fn test_nlst_recursive() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("a/b/c")).unwrap();
+        std::fs::write(root.path().join("top.txt"), b"").unwrap();
+        std::fs::write(root.path().join("a/one.txt"), b"").unwrap();
+        std::fs::write(root.path().join("a/b/two.txt"), b"").unwrap();
+        std::fs::write(root.path().join("a/b/c/three.txt"), b"").unwrap();
+        std::fs::write(root.path().join("config.toml"), b"").unwrap();
+        let config = Config {
+            max_list_depth: Some(2),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+
+        assert_eq!(
+            client.list("NLST -R").await,
+            ["a", "a/b", "a/b/c", "a/b/two.txt", "a/one.txt", "top.txt"]
+        );
+        assert_eq!(client.list("NLST -R a").await, ["b", "b/c", "b/c/three.txt", "b/two.txt", "one.txt"]);
+        assert_eq!(client.list("NLST a").await, ["b", "one.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_during_pasv() {
+        let root = tempfile::tempdir().unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        let reply = client.cmd("PASV").await;
+        let fields: Vec<u16> = reply[4..].trim().split(',').map(|field| field.parse().unwrap()).collect();
+        let port = fields[4] << 8 | fields[5];
+        drop(client);
+
+        let mut released = false;
+        for _ in 0..50 {
+            tokio::time::delay_for(std::time::Duration::from_millis(20)).await;
+            if TcpStream::connect(("127.0.0.1", port)).await.is_err() {
+                released = true;
+                break;
+            }
+        }
+        assert!(released, "port {} still listening", port);
+    }
+
+    #[tokio::test]
+    async fn test_quit_with_data_connection() {
+        let root = tempfile::tempdir().unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        let mut data = client.pasv().await;
+        assert!(client.cmd("QUIT").await.starts_with("221"));
+        let mut received = vec![];
+        data.read_to_end(&mut received).await.unwrap();
+        assert!(received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_directory_rules() {
+        use crate::config::DirectoryRule;
+
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("pub/incoming")).unwrap();
+        std::fs::write(root.path().join("pub/readme.txt"), b"hello").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(root.path().join("pub"), root.path().join("alias")).unwrap();
+        let rule = |path: &str, permissions: &[&str]| DirectoryRule {
+            path: path.to_owned(),
+            permissions: permissions.iter().map(|name| name.to_string()).collect(),
+        };
+        let config = Config {
+            directory_rules: Some(vec![rule("/pub", &["read", "list"]), rule("pub/incoming", &["write"])]),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+
+        assert_eq!(client.retr("pub/readme.txt").await, b"hello");
+        assert_eq!(client.list("NLST pub").await, ["incoming", "readme.txt"]);
+        let _data = client.pasv().await;
+        assert_eq!(client.cmd("STOR pub/new.txt").await, "550 Permission denied\r\n");
+        assert!(client.cmd("DELE pub/readme.txt").await.starts_with("550"));
+        assert!(root.path().join("pub/readme.txt").exists());
+
+        client.cwd("pub/incoming").await;
+        client.stor("upload.txt", b"dropped").await;
+        let _data = client.pasv().await;
+        assert!(client.cmd("LIST").await.starts_with("550"));
+        let _data = client.pasv().await;
+        assert!(client.cmd("RETR upload.txt").await.starts_with("550"));
+        assert!(client.cmd("DELE upload.txt").await.starts_with("550"));
+
+        // Elsewhere only the user permissions apply.
+        client.cwd("/").await;
+        client.stor("free.txt", b"anything").await;
+        assert!(client.cmd("DELE free.txt").await.starts_with("250"));
+        #[cfg(unix)]
+        assert!(client.cmd("DELE alias/readme.txt").await.starts_with("550"));
+    }
+
+    #[tokio::test]
+    async fn test_dropbox_dirs() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("incoming")).unwrap();
+        std::fs::write(root.path().join("incoming/theirs.txt"), b"secret").unwrap();
+        let config = Config {
+            dropbox_dirs: Some(vec!["incoming".to_owned()]),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+
+        client.stor("incoming/mine.txt", b"submission").await;
+        assert_eq!(std::fs::read(root.path().join("incoming/mine.txt")).unwrap(), b"submission");
+        let _data = client.pasv().await;
+        assert_eq!(client.cmd("RETR incoming/theirs.txt").await, "550 Permission denied\r\n");
+        let _data = client.pasv().await;
+        assert!(client.cmd("LIST incoming").await.starts_with("550"));
+        let _data = client.pasv().await;
+        assert!(client.cmd("NLST incoming/*.txt").await.starts_with("550"));
+        assert!(client.cmd("SIZE incoming/theirs.txt").await.starts_with("550"));
+        assert!(client.cmd("DELE incoming/theirs.txt").await.starts_with("550"));
+        client.cwd("incoming").await;
+        let _data = client.pasv().await;
+        assert!(client.cmd("NLST").await.starts_with("550"));
+        assert_eq!(client.list("NLST /").await, ["incoming"]);
+        let listing = client.list("NLST -R /").await;
+        assert!(!listing.contains(&"incoming/mine.txt".to_owned()), "{:?}", listing);
+        assert!(!listing.contains(&"incoming/theirs.txt".to_owned()), "{:?}", listing);
+    }
+
+    #[tokio::test]
+    async fn test_advertise_utf8() {
+        let root = tempfile::tempdir().unwrap();
+        let mut client = TestClient::login(root.path()).await;
+        assert!(!client.cmd("FEAT").await.contains(" UTF8\r\n"));
+
+        let config = Config {
+            advertise_utf8: Some(true),
+            ..test_config()
+        };
+        let addr = start_server(root.path(), config).await;
+        let mut stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        let mut greeting = String::new();
+        stream.read_line(&mut greeting).await.unwrap();
+        assert_eq!(greeting, "220 Welcome to this FTP server! (UTF8)\r\n");
+        drop(stream);
+        let mut client = TestClient::connect(addr).await;
+        assert!(client.cmd("USER ferris").await.starts_with("230"));
+        assert!(client.cmd("FEAT").await.contains(" UTF8\r\n"));
+    }
+
+    #[test]
+    fn test_add_file_info_vanished_entry() {
+        use super::add_file_info;
+        use crate::clock::SystemClock;
+
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("dir")).unwrap();
+        std::fs::write(root.path().join("gone.txt"), b"soon deleted").unwrap();
+        let entries: Vec<PathBuf> = std::fs::read_dir(root.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        std::fs::remove_file(root.path().join("gone.txt")).unwrap();
+
+        let mut out = vec![];
+        for entry in entries {
+            add_file_info(entry, &SystemClock, &mut out);
+        }
+        let listing = String::from_utf8(out).unwrap();
+        assert_eq!(listing.lines().count(), 1, "{:?}", listing);
+        assert!(listing.starts_with("drw-rw-rw- 1 anonymous anonymous "), "{:?}", listing);
+        assert!(listing.ends_with(" dir/\r\n"), "{:?}", listing);
+    }
+
+    #[tokio::test]
+    async fn test_default_dir() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("incoming/crab")).unwrap();
+        let mut config = test_config();
+        config.default_dir = Some("incoming".to_owned());
+        config.users.push(User {
+            name: "crab".to_owned(),
+            password: "".to_owned(),
+            default_dir: Some("incoming/crab".to_owned()),
+            ..Default::default()
+        });
+        config.users.push(User {
+            name: "lost".to_owned(),
+            password: "".to_owned(),
+            default_dir: Some("../..".to_owned()),
+            ..Default::default()
+        });
+        let addr = start_server(root.path(), config).await;
+        let mut client = TestClient::connect(addr).await;
+
+        assert!(client.cmd("USER ferris").await.starts_with("230"));
+        assert_eq!(client.pwd().await, "/incoming");
+        assert!(client.cmd("USER crab").await.starts_with("230"));
+        assert_eq!(client.pwd().await, "/incoming/crab");
+        // Outside of the server root, the session starts at the root.
+        assert!(client.cmd("USER lost").await.starts_with("230"));
+        assert_eq!(client.pwd().await, "/");
+    }
+
+    #[tokio::test]
+    async fn test_default_dir_under_home() {
+        use std::sync::Arc;
+
+        use async_trait::async_trait;
+
+        use super::{serve_shared, Shared};
+        use crate::auth::{Authenticator, Permissions, UserInfo};
+
+        struct Homes;
+
+        #[async_trait]
+        impl Authenticator for Homes {
+            async fn authenticate(&self, user: &str, _: &str) -> Option<UserInfo> {
+                Some(UserInfo {
+                    home: Some(PathBuf::from(format!("home/{}", user))),
+                    ..UserInfo::new(Permissions::user())
+                })
+            }
+        }
+
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("home/crab/inbox")).unwrap();
+        std::fs::create_dir_all(root.path().join("home/lost")).unwrap();
+        let mut config = test_config();
+        config.users.push(User {
+            name: "crab".to_owned(),
+            default_dir: Some("inbox".to_owned()),
+            ..Default::default()
+        });
+        config.users.push(User {
+            name: "lost".to_owned(),
+            default_dir: Some("inbox".to_owned()),
+            ..Default::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut shared = Shared::new(config);
+        shared.authenticator = Arc::new(Homes);
+        tokio::spawn(serve_shared(vec![(listener, None)], root.path().canonicalize().unwrap(), Arc::new(shared)));
+        let mut client = TestClient::connect(addr).await;
+
+        assert!(client.user("crab", "").await.starts_with("230"));
+        assert_eq!(client.pwd().await, "/home/crab/inbox");
+        // Without the directory in their home, they start in the home.
+        assert!(client.user("lost", "").await.starts_with("230"));
+        assert_eq!(client.pwd().await, "/home/lost");
+    }
+
+    #[tokio::test]
+    async fn test_data_buffer_size() {
+        use futures::StreamExt;
+        use std::pin::Pin;
+        use std::sync::{Arc, Mutex};
+        use std::task::{Context, Poll};
+        use tokio::io::AsyncRead;
+        use tokio_util::codec::Framed;
+
+        use super::{Client, Connection, ControlStream, Shared};
+        use crate::codec::FtpCodec;
+
+        // Serves `content`, recording the size of the buffer of every read.
+        struct RecordingFile {
+            content: io::Cursor<Vec<u8>>,
+            reads: Arc<Mutex<Vec<usize>>>,
+        }
+
+        impl AsyncRead for RecordingFile {
+            fn poll_read(mut self: Pin<&mut Self>, _: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+                self.reads.lock().unwrap().push(buf.len());
+                Poll::Ready(io::Read::read(&mut self.content, buf))
+            }
+        }
+
+        let content: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut control = BufReader::new(TcpStream::connect(listener.local_addr().unwrap()).await.unwrap());
+        let (socket, addr) = listener.accept().await.unwrap();
+        let mut data = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (data_socket, _) = listener.accept().await.unwrap();
+
+        let socket: Box<dyn ControlStream> = Box::new(socket);
+        let (writer, reader) = Framed::new(socket, FtpCodec).split();
+        let connection = Connection { id: 1, addr };
+        let config = Config {
+            data_buffer_size: Some(100),
+            ..test_config()
+        };
+        let mut client = Client::new(writer, reader, connection, std::env::temp_dir(), Arc::new(Shared::new(config)));
+        client.open_data_connection(data_socket);
+
+        let reads = Arc::new(Mutex::new(vec![]));
+        let file = RecordingFile {
+            content: io::Cursor::new(content.clone()),
+            reads: reads.clone(),
+        };
+        let _client = client.send_file(file, None).await.unwrap();
+        let mut reply = String::new();
+        control.read_line(&mut reply).await.unwrap();
+        assert!(reply.starts_with("226"), "{}", reply);
+        let mut received = vec![];
+        data.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, content);
+        // Ten full reads, then the one finding the end of the file.
+        assert_eq!(*reads.lock().unwrap(), [100; 11]);
+
+        // Uploads go through buffers of the same size.
+        let root = tempfile::tempdir().unwrap();
+        let config = Config {
+            data_buffer_size: Some(100),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+        client.stor("copy.bin", &content).await;
+        assert_eq!(std::fs::read(root.path().join("copy.bin")).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn test_pasv_dual_stack() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("data.bin"), b"data").unwrap();
+        // Without IPv6 there's no dual-stack listener to test.
+        let listener = match TcpListener::bind("[::]:0").await {
+            Ok(listener) => listener,
+            Err(_) => return,
+        };
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve(vec![listener], root.path().canonicalize().unwrap(), test_config()));
+
+        let mut client = TestClient::connect(([127, 0, 0, 1], port).into()).await;
+        assert!(client.cmd("USER ferris").await.starts_with("230"));
+        assert_eq!(client.transfer("RETR data.bin").await, b"data");
+    }
+
+    #[tokio::test]
+    async fn test_rang() {
+        let root = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..100).collect();
+        std::fs::write(root.path().join("data.bin"), &content).unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        assert!(!client.cmd("FEAT").await.contains("RANG"));
+        assert_eq!(client.cmd("RANG 10 20").await, "350 Next RETR sends bytes 10 up to 20, excluded\r\n");
+        assert_eq!(client.transfer("RETR data.bin").await, &content[10..20]);
+        // The range only applies to one transfer.
+        assert_eq!(client.transfer("RETR data.bin").await, content);
+        // An end past the end of the file stops there.
+        assert!(client.cmd("RANG 90 500").await.starts_with("350"));
+        assert_eq!(client.transfer("RETR data.bin").await, &content[90..]);
+        // REST drops the end of a previous RANG.
+        assert!(client.cmd("RANG 10 20").await.starts_with("350"));
+        assert!(client.cmd("REST 95").await.starts_with("350"));
+        assert_eq!(client.transfer("RETR data.bin").await, &content[95..]);
+        for range in ["RANG 20 10", "RANG 10", "RANG 10 x"] {
+            assert_eq!(client.cmd(range).await, "501 Invalid range\r\n");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_tls() {
+        use std::sync::Arc;
+
+        use tokio_rustls::rustls::{Certificate, ClientConfig, ProtocolVersion};
+        use tokio_rustls::webpki::DNSNameRef;
+        use tokio_rustls::TlsConnector;
+
+        use super::{serve_shared, Shared};
+        use crate::tls;
+
+        let root = tempfile::tempdir().unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        std::fs::write(root.path().join("cert.pem"), cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(root.path().join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+        let acceptor = tls::acceptor(&root.path().join("cert.pem"), &root.path().join("key.pem"), Some("1.3")).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config {
+            login_timeout: Some(1),
+            ..test_config()
+        };
+        let shared = Arc::new(Shared {
+            tls: Some(acceptor),
+            ..Shared::new(config)
+        });
+        tokio::spawn(serve_shared(vec![(listener, None)], root.path().canonicalize().unwrap(), shared));
+
+        let mut config = ClientConfig::new();
+        config.root_store.add(&Certificate(cert.serialize_der().unwrap())).unwrap();
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+
+        let mut client = TestClient::connect(addr).await;
+        assert!(client.cmd("FEAT").await.contains("\r\n AUTH TLS\r\n PBSZ\r\n PROT\r\n"));
+        assert!(client.cmd("PBSZ 0").await.starts_with("503"));
+        assert!(client.cmd("AUTH KERBEROS").await.starts_with("504"));
+        assert_eq!(client.cmd("AUTH SSL").await, "234 Starting TLS\r\n");
+        let connector = TlsConnector::from(Arc::new(config.clone()));
+        let mut control = BufReader::new(connector.connect(domain, client.into_inner()).await.unwrap());
+        let mut reply = String::new();
+        for (command, expected) in [
+            ("PBSZ 0", "200 PBSZ=0\r\n"),
+            ("PROT P", "536 Data connections can't be protected\r\n"),
+            ("PROT C", "200 Protection level set to Clear\r\n"),
+        ] {
+            control.write_all(format!("{}\r\n", command).as_bytes()).await.unwrap();
+            reply.clear();
+            control.read_line(&mut reply).await.unwrap();
+            assert_eq!(reply, expected);
+        }
+        control.write_all(b"USER ferris\r\n").await.unwrap();
+        reply.clear();
+        control.read_line(&mut reply).await.unwrap();
+        assert!(reply.starts_with("230"), "{}", reply);
+        control.write_all(b"AUTH TLS\r\n").await.unwrap();
+        reply.clear();
+        control.read_line(&mut reply).await.unwrap();
+        assert!(reply.starts_with("503"), "{}", reply);
+
+        // TLS 1.2 is below the minimum version.
+        config.versions = vec![ProtocolVersion::TLSv1_2];
+        let mut client = TestClient::connect(addr).await;
+        assert!(client.cmd("AUTH TLS-C").await.starts_with("234"));
+        let connector = TlsConnector::from(Arc::new(config));
+        assert!(connector.connect(domain, client.into_inner()).await.is_err());
+
+        // A client not starting the handshake after AUTH gets disconnected.
+        let mut client = TestClient::connect(addr).await;
+        assert!(client.cmd("AUTH TLS").await.starts_with("234"));
+        let mut silent = client.into_inner();
+        let read = tokio::time::timeout(std::time::Duration::from_secs(5), silent.read(&mut [0; 16])).await;
+        assert!(matches!(read, Ok(Ok(0))), "{:?}", read);
+    }
+
+    #[tokio::test]
+    async fn test_auth_without_certificate() {
+        let root = tempfile::tempdir().unwrap();
+        let addr = start_server(root.path(), test_config()).await;
+        let mut client = TestClient::connect(addr).await;
+        assert!(client.cmd("AUTH TLS").await.starts_with("502"));
+        assert!(!client.cmd("FEAT").await.contains(" AUTH TLS"));
+        assert!(client.cmd("USER ferris").await.starts_with("230"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_chroot_option() {
+        use super::enter_chroot;
+
+        let config = Config::parse("chroot = true\nusers = []").unwrap();
+        assert_eq!(config.chroot, Some(true));
+        // Fails whether running as root or not, without confining the tests.
+        let error = enter_chroot(Path::new("/nonexistent/server/root")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_retr_read_error() {
+        use futures::StreamExt;
+        use std::pin::Pin;
+        use std::sync::Arc;
+        use std::task::{Context, Poll};
+        use tokio::io::AsyncRead;
+        use tokio_util::codec::Framed;
+
+        use super::{Client, Connection, ControlStream, Shared};
+        use crate::codec::FtpCodec;
+
+        // Reads "partial" and then fails, like a disk going bad.
+        struct FailingFile {
+            read: bool,
+        }
+
+        impl AsyncRead for FailingFile {
+            fn poll_read(mut self: Pin<&mut Self>, _: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+                if self.read {
+                    return Poll::Ready(Err(io::Error::other("input/output error")));
+                }
+                self.read = true;
+                buf[..7].copy_from_slice(b"partial");
+                Poll::Ready(Ok(7))
+            }
+        }
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut control = BufReader::new(TcpStream::connect(listener.local_addr().unwrap()).await.unwrap());
+        let (socket, addr) = listener.accept().await.unwrap();
+        let mut data = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (data_socket, _) = listener.accept().await.unwrap();
+
+        let socket: Box<dyn ControlStream> = Box::new(socket);
+        let (writer, reader) = Framed::new(socket, FtpCodec).split();
+        let connection = Connection { id: 1, addr };
+        let mut client = Client::new(writer, reader, connection, std::env::temp_dir(), Arc::new(Shared::new(test_config())));
+        client.open_data_connection(data_socket);
+
+        // The session outlives the failed transfer.
+        let client = client.send_file(FailingFile { read: false }, None).await.unwrap();
+        let mut reply = String::new();
+        control.read_line(&mut reply).await.unwrap();
+        assert_eq!(reply, "451 Error reading the file; transfer aborted\r\n");
+        let mut received = vec![];
+        data.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"partial");
+        assert!(client.data_writer.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_session_summary() {
+        use futures::StreamExt;
+        use std::sync::Arc;
+        use tokio_util::codec::Framed;
+
+        use super::{Client, Connection, ControlStream, Shared};
+        use crate::cmd::Command;
+        use crate::codec::FtpCodec;
+
+        let root = tempfile::tempdir().unwrap();
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut control = BufReader::new(TcpStream::connect(listener.local_addr().unwrap()).await.unwrap());
+        let (socket, addr) = listener.accept().await.unwrap();
+        let mut data = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (data_socket, _) = listener.accept().await.unwrap();
+
+        let socket: Box<dyn ControlStream> = Box::new(socket);
+        let (writer, reader) = Framed::new(socket, FtpCodec).split();
+        let connection = Connection { id: 1, addr };
+        let shared = Arc::new(Shared::new(test_config()));
+        let mut client = Client::new(writer, reader, connection, root.path().canonicalize().unwrap(), shared);
+        assert_eq!(
+            client.summary(),
+            "Session summary: user=- commands=0 uploaded=0 downloaded=0 duration=0s result=error"
+        );
+
+        client = client.handle_cmd(Command::User("ferris".to_owned())).await.unwrap();
+        client.open_data_connection(data_socket);
+        data.write_all(b"twelve bytes").await.unwrap();
+        drop(data);
+        client = client.handle_cmd(Command::Stor(PathBuf::from("upload.txt"))).await.unwrap();
+        let mut reply = String::new();
+        for _ in 0..3 {
+            control.read_line(&mut reply).await.unwrap();
+        }
+        assert!(reply.ends_with("226 Transfer done\r\n"), "{}", reply);
+        client.result = Some("quit");
+        assert_eq!(
+            client.summary(),
+            "Session summary: user=ferris commands=2 uploaded=12 downloaded=0 duration=0s result=quit"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_as_options() {
+        use super::lookup_ids;
+
+        let config = Config::parse("run_as_user = \"root\"\nrun_as_group = \"0\"\nusers = []").unwrap();
+        assert_eq!(config.run_as_user.as_deref(), Some("root"));
+        assert_eq!(config.run_as_group.as_deref(), Some("0"));
+        assert!(config.validate().is_ok());
+
+        let (uid, gid) = lookup_ids("root", None).unwrap();
+        assert!(uid.is_root() && gid.as_raw() == 0);
+        let (uid, gid) = lookup_ids("65534", Some("65534")).unwrap();
+        assert_eq!((uid.as_raw(), gid.as_raw()), (65534, 65534));
+        assert!(lookup_ids("no such user", None).is_err());
+        assert!(lookup_ids("root", Some("no such group")).is_err());
+        // A bare uid has no primary group to fall back on.
+        assert!(lookup_ids("4242424", None).is_err());
+
+        let config = Config::parse("run_as_group = \"nogroup\"\nusers = []").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_site_help() {
+        use super::SITE_COMMANDS;
+
+        let root = tempfile::tempdir().unwrap();
+        let config = Config {
+            admin: Some(User {
+                name: "admin".to_owned(),
+                password: "".to_owned(),
+                ..Default::default()
+            }),
+            ..test_config()
+        };
+        let addr = start_server(root.path(), config).await;
+        let mut client = TestClient::connect(addr).await;
+        assert!(client.cmd("USER ferris").await.starts_with("230"));
+        let help = client.cmd("SITE HELP").await;
+        assert!(help.starts_with("214-The following SITE commands are recognized:\r\n"), "{}", help);
+        assert!(help.contains("\r\n IDLE    Show the idle timeout, or set it (admin only)\r\n"), "{}", help);
+        assert!(!help.contains(" KICK "), "{}", help);
+        assert!(help.ends_with("214 Help OK\r\n"), "{}", help);
+
+        let mut admin = TestClient::connect(addr).await;
+        assert!(admin.cmd("USER admin").await.starts_with("230"));
+        let help = admin.cmd("SITE HELP").await;
+        for command in SITE_COMMANDS {
+            assert!(help.contains(&format!("\r\n {:<8}", command.name)), "{}", help);
+        }
+        // Whatever SITE HELP lists is implemented.
+        for command in SITE_COMMANDS {
+            let reply = admin.cmd(&format!("SITE {}", command.name)).await;
+            assert!(!reply.contains("Not implemented"), "{}", reply);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_single_file() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("somefile.txt"), b"hi").unwrap();
+        std::fs::write(root.path().join("a[1].txt"), b"hi").unwrap();
+        std::fs::write(root.path().join("a1.txt"), b"hi").unwrap();
+        std::fs::write(root.path().join("config.toml"), b"users = []").unwrap();
+        let mut client = TestClient::login(root.path()).await;
+
+        let listing = String::from_utf8(client.transfer("LIST somefile.txt").await).unwrap();
+        assert_eq!(listing.lines().count(), 1, "{}", listing);
+        assert!(listing.starts_with('-') && listing.ends_with(" somefile.txt\r\n"), "{}", listing);
+        // Names that look like patterns are taken literally when they exist.
+        assert_eq!(client.list("LIST a[1].txt").await, ["a[1].txt"]);
+        assert_eq!(client.list("LIST a[0-9].txt").await, ["a1.txt"]);
+        // A file can't hold anything to match a pattern against.
+        let _data = client.pasv().await;
+        assert!(client.cmd("LIST somefile.txt/*").await.starts_with("550"));
+        let _data = client.pasv().await;
+        assert!(client.cmd("LIST config.toml").await.starts_with("550"));
+        assert!(client.cmd("NOOP").await.starts_with("200"));
+    }
+
+    #[test]
+    fn test_list_date() {
+        use super::list_date;
+
+        // 2021-06-15 12:00:00 UTC
+        let now = time::Timespec::new(1_623_758_400, 0);
+        let date = |sec| list_date(time::at_utc(time::Timespec::new(sec, 0)), now);
+        assert_eq!(date(1_623_758_400 - 86_400), "Jun 14 12:00");
+        assert_eq!(date(1_609_462_800), "Jan  1 01:00");
+        // More than six months ago, or in the future.
+        assert_eq!(date(1_593_000_000), "Jun 24  2020");
+        assert_eq!(date(1_623_758_400 + 3_600), "Jun 15  2021");
+    }
+
+    #[test]
+    fn test_add_file_info_date() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        use super::add_file_info;
+        use crate::clock::FixedClock;
+
+        // 2021-06-15 12:00:00 UTC
+        let clock = FixedClock(time::Timespec::new(1_623_758_400, 0));
+        let root = tempfile::tempdir().unwrap();
+        let listing = |sec| {
+            let path = root.path().join("file.txt");
+            let file = std::fs::File::create(&path).unwrap();
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(sec)).unwrap();
+            let mut out = vec![];
+            add_file_info(path, &clock, &mut out);
+            String::from_utf8(out).unwrap()
+        };
+        // Mid-month so the day is the same whatever the local time zone.
+        let recent = listing(1_623_758_400 - 3 * 86_400);
+        assert!(recent.starts_with("-rw-rw-rw- 1 anonymous anonymous 0 Jun 12 "), "{:?}", recent);
+        assert!(recent.ends_with(":00 file.txt\r\n"), "{:?}", recent);
+        assert_eq!(
+            listing(992_606_400),
+            "-rw-rw-rw- 1 anonymous anonymous 0 Jun 15  2001 file.txt\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_fixed_clock() {
+        use std::sync::Arc;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        use crate::clock::FixedClock;
+        use crate::testing::start_server_with_clock;
+
+        let root = tempfile::tempdir().unwrap();
+        let file = std::fs::File::create(root.path().join("old.txt")).unwrap();
+        file.set_len(42).unwrap();
+        // 2001-06-15 12:00:00 UTC
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(992_606_400)).unwrap();
+        // The file is old on 2001-12-31 but recent a month before.
+        for &(now, date) in &[(1_009_800_000, "Jun 15  2001"), (995_198_400, "Jun 15 ")] {
+            let clock = Arc::new(FixedClock(time::Timespec::new(now, 0)));
+            let addr = start_server_with_clock(root.path(), test_config(), clock).await;
+            let mut client = TestClient::connect(addr).await;
+            assert!(client.cmd("USER ferris").await.starts_with("230"));
+            let listing = String::from_utf8(client.transfer("LIST").await).unwrap();
+            let expected = format!("-rw-rw-rw- 1 anonymous anonymous 42 {}", date);
+            assert!(listing.starts_with(&expected), "{:?}", listing);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_list_entries() {
+        let root = tempfile::tempdir().unwrap();
+        for i in 0..5 {
+            std::fs::write(root.path().join(format!("file{}.txt", i)), b"").unwrap();
+        }
+        std::fs::create_dir(root.path().join("dir")).unwrap();
+        std::fs::write(root.path().join("dir/nested.txt"), b"").unwrap();
+        let config = Config {
+            max_list_entries: Some(3),
+            ..test_config()
+        };
+        let mut client = TestClient::login_with(root.path(), config).await;
+
+        for cmd in &["LIST", "NLST", "NLST -R"] {
+            let mut data = client.pasv().await;
+            assert!(client.cmd(cmd).await.starts_with("125"));
+            let mut listing = String::new();
+            data.read_to_string(&mut listing).await.unwrap();
+            assert_eq!(listing.lines().count(), 3, "{}: {:?}", cmd, listing);
+            assert_eq!(client.reply().await, "226 Listing truncated to 3 entries\r\n");
+        }
+        // Smaller listings are sent whole.
+        assert_eq!(client.list("NLST dir").await, ["nested.txt"]);
+    }
 }
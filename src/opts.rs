@@ -0,0 +1,144 @@
+//! OPTS, which sets the options of another command. Each command taking
+//! options has its handler in `HANDLERS`.
+
+use crate::ftp::{Answer, ResultCode};
+use crate::hash::HashAlgorithm;
+
+/// Facts MLST and MLSD can report, as RFC 3659 names them.
+pub const MLST_FACTS: [&str; 4] = ["type", "size", "modify", "perm"];
+
+/// What OPTS changed for the rest of the session.
+pub struct Options {
+    pub hash_algorithm: HashAlgorithm,
+    /// Facts selected for MLST and MLSD, all of them until OPTS MLST.
+    pub mlst_facts: Vec<&'static str>,
+    /// Set by OPTS UTF8 ON: names typed in another Unicode normalization
+    /// form than the one on disk still match.
+    pub utf8: bool,
+}
+
+impl Options {
+    pub fn new(hash_algorithm: HashAlgorithm) -> Options {
+        Options {
+            hash_algorithm,
+            mlst_facts: MLST_FACTS.to_vec(),
+            utf8: false,
+        }
+    }
+}
+
+/// Handles the options of a command: what follows its name, if anything.
+type Handler = fn(&mut Options, Option<&str>) -> Answer;
+
+const HANDLERS: &[(&str, Handler)] = &[("HASH", hash), ("MLST", mlst), ("UTF8", utf8)];
+
+/// Runs OPTS with `args`, the command name followed by its options.
+pub fn dispatch(options: &mut Options, args: &str) -> Answer {
+    let mut args = args.trim().splitn(2, ' ');
+    let command = args.next().unwrap_or_default().to_uppercase();
+    let value = args.next().map(str::trim).filter(|value| !value.is_empty());
+    match HANDLERS.iter().find(|(name, _)| *name == command) {
+        Some((_, handler)) => handler(options, value),
+        None => Answer::new(ResultCode::InvalidParameterOrArgument, "Option not understood"),
+    }
+}
+
+/// Switches the algorithm used by HASH, or reports the current one. SITE
+/// HASH does the same.
+pub fn hash(options: &mut Options, name: Option<&str>) -> Answer {
+    match name.map(HashAlgorithm::from_name) {
+        Some(Some(algorithm)) => {
+            options.hash_algorithm = algorithm;
+            Answer::new(ResultCode::Ok, algorithm.name())
+        }
+        Some(None) => Answer::new(ResultCode::InvalidParameterOrArgument, "Unknown algorithm"),
+        None => Answer::new(ResultCode::Ok, options.hash_algorithm.name()),
+    }
+}
+
+/// Selects the facts MLST and MLSD report, "type;size;" style. Unknown facts
+/// are ignored and no facts at all selects none, as RFC 3659 says; the reply
+/// lists what got selected.
+fn mlst(options: &mut Options, facts: Option<&str>) -> Answer {
+    let requested: Vec<String> = facts
+        .unwrap_or_default()
+        .split(';')
+        .map(str::to_lowercase)
+        .collect();
+    options.mlst_facts = MLST_FACTS
+        .iter()
+        .copied()
+        .filter(|fact| requested.iter().any(|requested| requested == fact))
+        .collect();
+    let mut message = String::from("MLST OPTS");
+    if !options.mlst_facts.is_empty() {
+        message.push(' ');
+        for fact in &options.mlst_facts {
+            message.push_str(fact);
+            message.push(';');
+        }
+    }
+    Answer::new(ResultCode::Ok, &message)
+}
+
+// Paths are always decoded as UTF-8, ON only adds the matching of names
+// across normalization forms.
+fn utf8(options: &mut Options, value: Option<&str>) -> Answer {
+    match value.map(str::to_uppercase).as_deref() {
+        Some("ON") => {
+            options.utf8 = true;
+            Answer::new(ResultCode::Ok, "Always in UTF8 mode")
+        }
+        Some("OFF") => Answer::new(ResultCode::CommandNotImplementedForThatParameter, "UTF8 can't be turned off"),
+        _ => Answer::new(ResultCode::InvalidParameterOrArgument, "Usage: OPTS UTF8 ON"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dispatch, Options};
+    use crate::ftp::ResultCode;
+    use crate::hash::HashAlgorithm;
+
+    fn reply(options: &mut Options, args: &str) -> (ResultCode, String) {
+        let answer = dispatch(options, args);
+        (answer.code, answer.message)
+    }
+
+    #[test]
+    fn test_hash() {
+        let mut options = Options::new(HashAlgorithm::Sha256);
+        assert_eq!(reply(&mut options, "HASH"), (ResultCode::Ok, "SHA-256".to_owned()));
+        assert_eq!(reply(&mut options, "hash md5"), (ResultCode::Ok, "MD5".to_owned()));
+        assert_eq!(options.hash_algorithm, HashAlgorithm::Md5);
+        assert_eq!(reply(&mut options, "HASH SHA-512").0, ResultCode::InvalidParameterOrArgument);
+        assert_eq!(options.hash_algorithm, HashAlgorithm::Md5);
+    }
+
+    #[test]
+    fn test_mlst() {
+        let mut options = Options::new(HashAlgorithm::Sha256);
+        assert_eq!(options.mlst_facts, ["type", "size", "modify", "perm"]);
+        assert_eq!(reply(&mut options, "MLST size;Type;unique;"), (ResultCode::Ok, "MLST OPTS type;size;".to_owned()));
+        assert_eq!(options.mlst_facts, ["type", "size"]);
+        assert_eq!(reply(&mut options, "MLST"), (ResultCode::Ok, "MLST OPTS".to_owned()));
+        assert!(options.mlst_facts.is_empty());
+    }
+
+    #[test]
+    fn test_utf8() {
+        let mut options = Options::new(HashAlgorithm::Sha256);
+        assert!(!options.utf8);
+        assert_eq!(reply(&mut options, "UTF8 on"), (ResultCode::Ok, "Always in UTF8 mode".to_owned()));
+        assert!(options.utf8);
+        assert_eq!(reply(&mut options, "UTF8 OFF").0, ResultCode::CommandNotImplementedForThatParameter);
+        assert_eq!(reply(&mut options, "UTF8").0, ResultCode::InvalidParameterOrArgument);
+    }
+
+    #[test]
+    fn test_unknown() {
+        let mut options = Options::new(HashAlgorithm::Sha256);
+        assert_eq!(reply(&mut options, "FOO BAR").0, ResultCode::InvalidParameterOrArgument);
+        assert_eq!(reply(&mut options, "").0, ResultCode::InvalidParameterOrArgument);
+    }
+}
@@ -0,0 +1,98 @@
+//! PROXY protocol v1, the header a load balancer sends ahead of the proxied
+//! connection to tell the server where it really comes from.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Longest header allowed by the specification, CRLF included.
+const MAX_HEADER_LEN: usize = 107;
+
+fn invalid_header() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid PROXY protocol header")
+}
+
+/// Reads the header, one byte at a time so nothing after it gets consumed,
+/// and returns the source address it announces. `None` means the proxy
+/// doesn't know it ("PROXY UNKNOWN").
+pub async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut line = Vec::with_capacity(MAX_HEADER_LEN);
+    while !line.ends_with(b"\r\n") {
+        if line.len() == MAX_HEADER_LEN {
+            return Err(invalid_header());
+        }
+        line.push(reader.read_u8().await?);
+    }
+    parse_header(&line[..line.len() - 2])
+}
+
+/// Parses a header line without its CRLF:
+/// "PROXY TCP4 <source> <destination> <source port> <destination port>".
+pub fn parse_header(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = str::from_utf8(line).map_err(|_| invalid_header())?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol, source, destination, source_port, destination_port] => {
+            let source: IpAddr = source.parse().map_err(|_| invalid_header())?;
+            let destination: IpAddr = destination.parse().map_err(|_| invalid_header())?;
+            let family_matches = match *protocol {
+                "TCP4" => source.is_ipv4() && destination.is_ipv4(),
+                "TCP6" => source.is_ipv6() && destination.is_ipv6(),
+                _ => false,
+            };
+            if !family_matches {
+                return Err(invalid_header());
+            }
+            let source_port = parse_port(source_port)?;
+            parse_port(destination_port)?;
+            Ok(Some(SocketAddr::new(source, source_port)))
+        }
+        _ => Err(invalid_header()),
+    }
+}
+
+// Ports are written in decimal without leading zeros.
+fn parse_port(port: &str) -> io::Result<u16> {
+    if port.len() > 1 && port.starts_with('0') {
+        return Err(invalid_header());
+    }
+    port.parse().map_err(|_| invalid_header())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_header, read_header};
+
+    #[test]
+    fn test_parse_header() {
+        assert_eq!(
+            parse_header(b"PROXY TCP4 192.0.2.10 192.0.2.1 56324 21").unwrap(),
+            Some("192.0.2.10:56324".parse().unwrap())
+        );
+        assert_eq!(
+            parse_header(b"PROXY TCP6 2001:db8::10 2001:db8::1 56324 21").unwrap(),
+            Some("[2001:db8::10]:56324".parse().unwrap())
+        );
+        assert_eq!(parse_header(b"PROXY UNKNOWN").unwrap(), None);
+        assert!(parse_header(b"PROXY TCP4 2001:db8::10 192.0.2.1 56324 21").is_err());
+        assert!(parse_header(b"PROXY TCP4 192.0.2.10 192.0.2.1 056324 21").is_err());
+        assert!(parse_header(b"PROXY TCP4 192.0.2.10 192.0.2.1 65536 21").is_err());
+        assert!(parse_header(b"PROXY TCP4 192.0.2.10 192.0.2.1 56324").is_err());
+        assert!(parse_header(b"USER ferris").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let mut input: &[u8] = b"PROXY TCP4 192.0.2.10 192.0.2.1 56324 21\r\nUSER ferris\r\n";
+        assert_eq!(read_header(&mut input).await.unwrap(), Some("192.0.2.10:56324".parse().unwrap()));
+        assert_eq!(input, b"USER ferris\r\n");
+
+        let mut input: &[u8] = &[b'x'; 200];
+        assert!(read_header(&mut input).await.is_err());
+        let mut input: &[u8] = b"PROXY TCP4";
+        assert!(read_header(&mut input).await.is_err());
+    }
+}
@@ -0,0 +1,192 @@
+//! A small FTP client used by the tests to drive a real server.
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::clock::Clock;
+use crate::config::{Config, User};
+use crate::{serve_shared, Shared};
+
+/// A config with a single user, `ferris`, who has an empty password.
+pub fn test_config() -> Config {
+    Config {
+        users: vec![User {
+            name: "ferris".to_owned(),
+            password: "".to_owned(),
+            ..Default::default()
+        }],
+        ..Config::default()
+    }
+}
+
+/// Serves plain FTP on `listeners`.
+pub async fn serve(listeners: Vec<TcpListener>, server_root: PathBuf, config: Config) -> io::Result<()> {
+    let listeners = listeners.into_iter().map(|listener| (listener, None)).collect();
+    serve_shared(listeners, server_root, Arc::new(Shared::new(config))).await
+}
+
+/// Starts a server on an ephemeral port and returns its address.
+pub async fn start_server(root: &Path, config: Config) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve(vec![listener], root.canonicalize().unwrap(), config));
+    addr
+}
+
+/// Like `start_server`, with the listing dates taken from `clock`.
+pub async fn start_server_with_clock(root: &Path, config: Config, clock: Arc<dyn Clock>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut shared = Shared::new(config);
+    shared.clock = clock;
+    tokio::spawn(serve_shared(vec![(listener, None)], root.canonicalize().unwrap(), Arc::new(shared)));
+    addr
+}
+
+pub struct TestClient {
+    control: BufReader<TcpStream>,
+}
+
+impl TestClient {
+    /// Starts a server with `test_config` and logs in as `ferris`.
+    pub async fn login(root: &Path) -> TestClient {
+        TestClient::login_with(root, test_config()).await
+    }
+
+    pub async fn login_with(root: &Path, config: Config) -> TestClient {
+        let addr = start_server(root, config).await;
+        let mut client = TestClient::connect(addr).await;
+        assert!(client.cmd("USER ferris").await.starts_with("230"));
+        client
+    }
+
+    pub async fn connect(addr: SocketAddr) -> TestClient {
+        let mut client = TestClient {
+            control: BufReader::new(TcpStream::connect(addr).await.unwrap()),
+        };
+        assert!(client.reply().await.starts_with("220"));
+        client
+    }
+
+    /// Connects the way a load balancer would, sending the PROXY protocol
+    /// `header` first.
+    pub async fn connect_proxied(addr: SocketAddr, header: &str) -> TestClient {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(format!("{}\r\n", header).as_bytes()).await.unwrap();
+        let mut client = TestClient {
+            control: BufReader::new(stream),
+        };
+        assert!(client.reply().await.starts_with("220"));
+        client
+    }
+
+    /// The control connection, to go on with it some other way (e.g. over
+    /// TLS after AUTH).
+    pub fn into_inner(self) -> TcpStream {
+        self.control.into_inner()
+    }
+
+    /// Sends USER and, if asked for one, PASS. Returns the last reply.
+    pub async fn user(&mut self, name: &str, password: &str) -> String {
+        let reply = self.cmd(&format!("USER {}", name)).await;
+        if !reply.starts_with("331") {
+            return reply;
+        }
+        self.cmd(&format!("PASS {}", password)).await
+    }
+
+    pub async fn send(&mut self, line: &str) {
+        self.control.write_all(format!("{}\r\n", line).as_bytes()).await.unwrap();
+    }
+
+    /// Reads a reply, all of its lines if it is a multi-line one.
+    pub async fn reply(&mut self) -> String {
+        let mut reply = String::new();
+        self.control.read_line(&mut reply).await.unwrap();
+        if reply.as_bytes().get(3) == Some(&b'-') {
+            let end = format!("{} ", &reply[..3]);
+            loop {
+                let mut line = String::new();
+                self.control.read_line(&mut line).await.unwrap();
+                reply.push_str(&line);
+                if line.is_empty() || line.starts_with(&end) {
+                    break;
+                }
+            }
+        }
+        reply
+    }
+
+    pub async fn cmd(&mut self, line: &str) -> String {
+        self.send(line).await;
+        self.reply().await
+    }
+
+    /// Returns the quoted directory of the PWD reply.
+    pub async fn pwd(&mut self) -> String {
+        let reply = self.cmd("PWD").await;
+        assert!(reply.starts_with("257"), "{}", reply);
+        let start = reply.find('"').unwrap() + 1;
+        let end = reply.rfind('"').unwrap();
+        reply[start..end].replace("\"\"", "\"")
+    }
+
+    pub async fn cwd(&mut self, directory: &str) {
+        let reply = self.cmd(&format!("CWD {}", directory)).await;
+        assert!(reply.starts_with("250"), "{}", reply);
+    }
+
+    /// Runs a listing command and returns the last word of each line, sorted.
+    pub async fn list(&mut self, cmd: &str) -> Vec<String> {
+        let listing = String::from_utf8(self.transfer(cmd).await).unwrap();
+        let mut names: Vec<String> = listing
+            .lines()
+            .filter_map(|line| line.split_whitespace().last())
+            .map(|name| name.to_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    pub async fn retr(&mut self, path: &str) -> Vec<u8> {
+        self.transfer(&format!("RETR {}", path)).await
+    }
+
+    pub async fn stor(&mut self, path: &str, content: &[u8]) {
+        let mut data = self.pasv().await;
+        let reply = self.cmd(&format!("STOR {}", path)).await;
+        assert!(reply.starts_with("125"), "{}", reply);
+        data.write_all(content).await.unwrap();
+        drop(data);
+        let reply = self.reply().await;
+        assert!(reply.starts_with("226"), "{}", reply);
+    }
+
+    pub async fn pasv(&mut self) -> TcpStream {
+        let reply = self.cmd("PASV").await;
+        assert!(reply.starts_with("227"), "{}", reply);
+        let fields: Vec<u16> = reply[4..]
+            .trim()
+            .split(',')
+            .map(|field| field.parse().unwrap())
+            .collect();
+        TcpStream::connect(("127.0.0.1", fields[4] << 8 | fields[5])).await.unwrap()
+    }
+
+    /// Opens a data connection, runs `cmd` and reads everything it sends.
+    pub async fn transfer(&mut self, cmd: &str) -> Vec<u8> {
+        let mut data = self.pasv().await;
+        let reply = self.cmd(cmd).await;
+        assert!(reply.starts_with("125"), "{}", reply);
+        let mut content = vec![];
+        data.read_to_end(&mut content).await.unwrap();
+        let reply = self.reply().await;
+        assert!(reply.starts_with("226"), "{}", reply);
+        content
+    }
+}
@@ -0,0 +1,55 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::{NoClientAuth, PrivateKey, ProtocolVersion, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKey> {
+    // The key can either be a PKCS#8 ("BEGIN PRIVATE KEY") or a PKCS#1
+    // ("BEGIN RSA PRIVATE KEY") one.
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut keys = pkcs8_private_keys(&mut reader).unwrap_or_default();
+    if keys.is_empty() {
+        let mut reader = BufReader::new(File::open(path)?);
+        keys = rsa_private_keys(&mut reader).unwrap_or_default();
+    }
+    keys.pop()
+        .ok_or_else(|| invalid_data(format!("no private key found in {}", path.display())))
+}
+
+/// The TLS versions from `min_version` ("1.2" or "1.3") on, newest first.
+/// Older versions aren't supported at all.
+pub fn versions(min_version: &str) -> Option<Vec<ProtocolVersion>> {
+    match min_version {
+        "1.2" => Some(vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2]),
+        "1.3" => Some(vec![ProtocolVersion::TLSv1_3]),
+        _ => None,
+    }
+}
+
+/// Builds the acceptor wrapping connections in TLS from a PEM certificate
+/// chain and its PEM private key, refusing versions older than
+/// `min_version` (TLS 1.2 when unset).
+pub fn acceptor(cert: &Path, key: &Path, min_version: Option<&str>) -> io::Result<TlsAcceptor> {
+    let chain = certs(&mut BufReader::new(File::open(cert)?))
+        .map_err(|_| invalid_data(format!("invalid certificate in {}", cert.display())))?;
+    if chain.is_empty() {
+        return Err(invalid_data(format!("no certificate found in {}", cert.display())));
+    }
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    if let Some(min_version) = min_version {
+        config.versions =
+            versions(min_version).ok_or_else(|| invalid_data(format!("unsupported TLS version {}", min_version)))?;
+    }
+    config
+        .set_single_cert(chain, load_key(key)?)
+        .map_err(|error| invalid_data(error.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}